@@ -1,2 +1 @@
-mod models;
-
+pub mod models;
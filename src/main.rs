@@ -1,120 +1,292 @@
-mod models;
+use futures::{sink, stream, Sink, SinkExt, Stream};
+use mqtt_broker::models::{broker::Broker, broker_actor::{spawn_broker, BrokerHandle, ConnectionQueues}, config::BrokerConfig, decode::decode, packet_buffer::PacketBuffer, error::MqttError, mqtt_headers::MqttHeaders, mqtt_types::{MqttPacketDispatcher, MqttPacketType}};
 
-use futures::SinkExt;
-use models::{broker::Broker, mqtt_types::{MqttPacketDispatcher, MqttPacketType}};
-
-use tokio::net::TcpListener;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::spawn;
-use tokio_tungstenite::{accept_async, tungstenite::protocol::Message};
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{sleep, sleep_until, timeout_at, Instant};
+use tokio_tungstenite::{accept_async, tungstenite::{protocol::Message, Error as WsError}};
 use futures_util::StreamExt;
-use std::{ops::Deref, sync::{Arc, Mutex}};
+use std::{future::Future, net::SocketAddr, ops::ControlFlow, sync::Arc, time::Duration};
 
 use log::{info, warn, error};
-use env_logger;
 
 const SERVER_ADDR: &str = "127.0.0.1";
 const PORT: &str = "1883";
+const UNKNOWN_PEER: &str = "unknown";
+const ACCEPT_BACKOFF_START: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+const OUTBOUND_QUEUE_SIZE: usize = 64;
 
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init();
     info!("logger initiated");
-    let dispatcher = Arc::new(MqttPacketDispatcher::new().expect("Failed to create dispatcher")); 
+    let dispatcher = MqttPacketDispatcher::new().expect("Failed to create dispatcher");
+    let config = BrokerConfig::default();
     let listener = TcpListener::bind(format!("{}:{}", SERVER_ADDR, PORT)).await?;
     info!("WebSocket server listening on ws://{}:{}", SERVER_ADDR, PORT);
+    let tcp_listener = TcpListener::bind(format!("{}:{}", SERVER_ADDR, config.tcp_port)).await?;
+    info!("MQTT server listening on tcp://{}:{}", SERVER_ADDR, config.tcp_port);
+
+    // Every connection holds a permit until its CONNECT is accepted, so a flood of connections that
+    // never complete the handshake cannot pile up. Both listeners draw from the same permits.
+    let pending_connects = Arc::new(Semaphore::new(config.max_pending_connects));
+    let broker = spawn_broker(Broker::with_config(config), dispatcher);
+    spawn(keep_alive_reaper(broker.clone()));
 
-    let broker = Arc::new(Mutex::new(Broker::new()));
+    tokio::try_join!(
+        serve(listener, broker.clone(), Transport::WebSocket, Arc::clone(&pending_connects)),
+        serve(tcp_listener, broker, Transport::Mqtt, pending_connects)
+    )?;
+    Ok(())
+}
 
-    while let Ok((stream, _)) = listener.accept().await {
-        info!("New client connected: {:?}", stream.peer_addr());
-        let dispatcher_clone = Arc::clone(&dispatcher);
-        let broker_clone = Arc::clone(&broker);
+// Accepts connections speaking `transport`, or either transport when `detect_transport` is configured.
+// A connection is closed right away when no permit is left in `pending_connects`.
+async fn serve(listener: TcpListener, broker: BrokerHandle, transport: Transport, pending_connects: Arc<Semaphore>) -> std::io::Result<()> {
+    let config = Arc::new(broker.config().await.map_err(std::io::Error::other)?);
+
+    loop {
+        let (stream, _) = accept_with_backoff(|| listener.accept()).await;
+        let peer = peer_label(stream.peer_addr());
+        let pending_connect = match Arc::clone(&pending_connects).try_acquire_owned() {
+            Ok(permit) => PendingConnect { _permit: permit, deadline: Instant::now() + config.connect_timeout },
+            Err(_) => {
+                warn!("Too many pending CONNECTs, closing connection from {}", peer);
+                continue;
+            }
+        };
+        info!("New client connected: {}", peer);
+        let broker_clone = broker.clone();
+        let config = Arc::clone(&config);
         spawn(async move {
-            match accept_async(stream).await {
-                Ok(ws_stream) => {
-                    info!("WebSocket connecion established");
-                    connection_handler(ws_stream, dispatcher_clone, broker_clone).await;
+            let transport = if config.detect_transport {
+                match timeout_at(pending_connect.deadline, detect_transport(&stream)).await {
+                    Ok(Ok(Some(transport))) => transport,
+                    Ok(Ok(None)) => {
+                        warn!("Connection from {} is neither MQTT nor a WebSocket upgrade, closing connection", peer);
+                        return;
+                    }
+                    Ok(Err(e)) => {
+                        error!("Failed to read the first byte from {}: {}", peer, e);
+                        return;
+                    }
+                    Err(_) => {
+                        warn!("Connection from {} sent nothing within the connect timeout, closing connection", peer);
+                        return;
+                    }
                 }
-                Err(e) => {
-                    error!("Failed to upgrade TCP connection to WebSocket: {}", e);
+            } else {
+                transport
+            };
+            match transport {
+                Transport::WebSocket => websocket_connection(stream, broker_clone, pending_connect, config.max_packet_size).await,
+                Transport::Mqtt => {
+                    info!("Raw MQTT connection established");
+                    let (sender, receiver) = tcp_transport(stream, config.max_packet_size);
+                    handle_connection(sender, receiver, broker_clone, pending_connect, config.max_packet_size).await;
                 }
             }
         });
     }
-    drop(listener);
-    Ok(())
 }
 
+// A connection whose CONNECT has not been accepted yet. It holds one of the pending connect permits
+// and is closed once the deadline passes, whether it is still in the WebSocket handshake or
+// connected but silent.
+struct PendingConnect {
+    _permit: OwnedSemaphorePermit,
+    deadline: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transport {
+    WebSocket,
+    Mqtt,
+}
+
+// A WebSocket upgrade is an HTTP request starting with `GET `, while a raw MQTT client must open
+// with a CONNECT [MQTT-3.1.0-1], so the first byte tells both apart without consuming it.
+async fn detect_transport(stream: &TcpStream) -> std::io::Result<Option<Transport>> {
+    let mut first_byte = [0; 1];
+    if stream.peek(&mut first_byte).await? == 0 {
+        return Ok(None);
+    }
+    Ok(match first_byte[0] {
+        b'G' => Some(Transport::WebSocket),
+        byte if byte >> 4 == MqttPacketType::Connect as u8 => Some(Transport::Mqtt),
+        _ => None,
+    })
+}
 
-async fn connection_handler(ws_stream: tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, dispatcher: Arc<MqttPacketDispatcher>, broker: Arc<Mutex<Broker>>) {
-    let (mut sender, mut receiver) = ws_stream.split(); // Split the stream
-    info!("sender: [{:?}]; receiver: [{:?}]", sender, receiver);
-    while let Some(message) = receiver.next().await {
+async fn websocket_connection(stream: TcpStream, broker: BrokerHandle, pending_connect: PendingConnect, max_packet_size: usize) {
+    match timeout_at(pending_connect.deadline, accept_async(stream)).await {
+        Ok(Ok(ws_stream)) => {
+            info!("WebSocket connecion established");
+            connection_handler(ws_stream, broker, pending_connect, max_packet_size).await;
+        }
+        Ok(Err(e)) => {
+            error!("Failed to upgrade TCP connection to WebSocket: {}", e);
+        }
+        Err(_) => {
+            warn!("WebSocket handshake did not complete within the connect timeout, closing connection");
+        }
+    }
+}
+
+// Raw MQTT over TCP has no message boundaries, so every packet is cut out of the stream
+// using the remaining length announced in its fixed header
+async fn read_packet<R: AsyncRead + Unpin>(reader: &mut R, max_packet_size: usize) -> std::io::Result<Option<Vec<u8>>> {
+    let mut packet = match reader.read_u8().await {
+        Ok(byte) => vec![byte],
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let fixed_header = loop {
+        match MqttHeaders::parse(&packet) {
+            Ok(fixed_header) => break fixed_header,
+            Err(MqttError::Truncated(_)) => packet.push(reader.read_u8().await?),
+            Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+        }
+    };
+    if fixed_header.packet_size() > max_packet_size {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, MqttError::PacketTooLarge(fixed_header.packet_size())));
+    }
+    let header_size = packet.len();
+    packet.resize(fixed_header.packet_size(), 0);
+    reader.read_exact(&mut packet[header_size..]).await?;
+    Ok(Some(packet))
+}
+
+// Presents a raw TCP connection like a WebSocket one, every MQTT packet being one binary message,
+// so both transports share `handle_connection`
+fn tcp_transport(stream: TcpStream, max_packet_size: usize) -> (impl Sink<Message, Error = WsError> + Unpin, impl Stream<Item = Result<Message, WsError>> + Unpin) {
+    let (reader, writer) = stream.into_split();
+    let receiver = Box::pin(stream::unfold(BufReader::new(reader), move |mut reader| async move {
+        match read_packet(&mut reader, max_packet_size).await {
+            Ok(Some(packet)) => Some((Ok(Message::Binary(packet)), reader)),
+            Ok(None) => None,
+            Err(e) => Some((Err(WsError::Io(e)), reader)),
+        }
+    }));
+    let sender = Box::pin(sink::unfold(writer, |mut writer, message: Message| async move {
+        match message {
+            Message::Binary(data) => writer.write_all(&data).await?,
+            Message::Close(_) => writer.shutdown().await?,
+            _ => {}
+        }
+        Ok::<_, WsError>(writer)
+    }));
+    (sender, receiver)
+}
+
+// Transient accept errors (e.g. EMFILE) must not take the whole server down, so log them and keep accepting,
+// backing off while the errors repeat.
+async fn accept_with_backoff<F, Fut, T>(mut accept: F) -> T
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = std::io::Result<T>>,
+{
+    let mut backoff = ACCEPT_BACKOFF_START;
+    loop {
+        match accept().await {
+            Ok(connection) => return connection,
+            Err(e) => {
+                error!("Failed to accept connection: {}, retrying in {:?}", e, backoff);
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+            }
+        }
+    }
+}
+
+// Clients that vanish without closing their connection are only noticed through their keep alive,
+// so periodically drop the ones that have been silent for too long.
+async fn keep_alive_reaper(broker: BrokerHandle) {
+    let interval = match broker.config().await {
+        Ok(config) => config.keep_alive_check_interval,
+        Err(_) => return,
+    };
+    loop {
+        sleep(interval).await;
+        match broker.reap_expired_clients().await {
+            Ok(expired) => {
+                if !expired.is_empty() {
+                    info!("Dropped {} clients with an expired keep alive", expired.len());
+                }
+            }
+            Err(e) => {
+                error!("{}, stopping the keep alive reaper.", e);
+                return;
+            }
+        }
+    }
+}
+
+// The socket can already be gone by the time we ask for its address, so fall back to a placeholder
+// instead of assuming the lookup succeeds.
+fn peer_label(peer_addr: std::io::Result<SocketAddr>) -> String {
+    match peer_addr {
+        Ok(addr) => addr.to_string(),
+        Err(e) => {
+            warn!("Failed to read peer address: {}", e);
+            UNKNOWN_PEER.to_string()
+        }
+    }
+}
+
+// Messages on priority topics are written out before anything waiting in the regular queue
+async fn next_outbound(priority_receiver: &mut mpsc::Receiver<Message>, outbound_receiver: &mut mpsc::Receiver<Message>) -> Option<Message> {
+    tokio::select! {
+        biased;
+        Some(message) = priority_receiver.recv() => Some(message),
+        message = outbound_receiver.recv() => message,
+    }
+}
+
+async fn connection_handler(ws_stream: tokio_tungstenite::WebSocketStream<TcpStream>, broker: BrokerHandle, pending_connect: PendingConnect, max_packet_size: usize) {
+    let (sender, receiver) = ws_stream.split(); // Split the stream
+    handle_connection(sender, receiver, broker, pending_connect, max_packet_size).await;
+}
+
+async fn handle_connection<S, R>(sender: S, mut receiver: R, broker: BrokerHandle, pending_connect: PendingConnect, max_packet_size: usize)
+where
+    S: Sink<Message, Error = WsError> + Send + Unpin + 'static,
+    R: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    // Responses and the messages routed to this client by other connections are queued here
+    // and written out by the writer task, so a slow client never holds up reading its packets
+    let (outbound_sender, outbound_receiver) = mpsc::channel(OUTBOUND_QUEUE_SIZE);
+    let (priority_sender, priority_receiver) = mpsc::channel(OUTBOUND_QUEUE_SIZE);
+    let queues = ConnectionQueues { sender: outbound_sender, priority_sender };
+    let mut writer = spawn(write_outbound(sender, priority_receiver, outbound_receiver));
+    let mut writer_finished = false;
+    let connect_deadline = pending_connect.deadline;
+    let mut connection = Connection { broker, queues, received: PacketBuffer::new(max_packet_size), client_id: None, pending_connect: Some(pending_connect) };
+    loop {
+        let message = tokio::select! {
+            message = receiver.next() => match message {
+                Some(message) => message,
+                None => break,
+            },
+            _ = &mut writer => {
+                writer_finished = true;
+                break;
+            }
+            _ = sleep_until(connect_deadline), if connection.pending_connect.is_some() => {
+                warn!("No CONNECT accepted within the connect timeout, closing connection.");
+                break;
+            }
+        };
         info!("Message: [{:?}]", message);
         match message {
             Ok(Message::Binary(data)) => {
-                info!("We go here");
-                let message_type = data[0] >> 4;  // Extract message type from the first byte
-                let message_length = data[1];     // Extract message length from the second byte
-                info!(
-                    "Received WebSocket message of type {} and length {}",
-                    message_type, message_length
-                );
-                let function = dispatcher.deref().handlers.get(&MqttPacketType::from_u8(message_type).unwrap()).unwrap();
-                 
-                // if let Ok(mut broker_guard) = broker.try_lock() {
-                //     function(&data, &mut *broker_guard);
-                // } else {
-                //     error!("Failed to acquire lock on broker: it's already in use.");
-                // }
-
-                let packet = if let Ok(mut broker_guard) = broker.try_lock() {
-                    let packet = function(&data, &mut *broker_guard);
-                    drop(broker_guard);
-                    Some(packet)
-                } else {
-                    error!("Failed to acquire lock on broker: it's already in use.");
-                    None
-                };
-
-                if let Some(ref packet_data) = packet {
-                    if packet_data.len() == 0 {
-                        error!("Not a real packet data, no sending");
-                        continue;
-                    }
-                    info!("packet_data: [{:?}]", packet_data);
-                    if sender.send(Message::Binary(packet_data.to_vec())).await.is_err() {
-                        error!("Failed to send packet of type: {:?}", packet_data[0] >> 4)
-                    } else {
-                        info!("Respoonded to Packet type: {:?}", message_type)
-                    }
+                if connection.handle_data(&data).await.is_break() {
+                    break;
                 }
-
-                
-                
-                
-                // match message_type {
-                //     1 => {
-                //         // CONNECT message
-                //         let connack_packet: Vec<u8> = vec![
-                //             0x20, // CONNACK Packet type
-                //             0x02, // Remaining length
-                //             0x00, // Connection accepted
-                //             0x00, // Connection accepted
-                //         ];
-
-                //         // Send CONNACK response as a WebSocket binary message
-                //         if ws_stream.send(Message::Binary(connack_packet)).await.is_err() {
-                //             eprintln!("Failed to send CONNACK packet");
-                //         } else {
-                //             println!("Responded to CONNECT");
-                //         }
-                //     }
-                //     t => {
-                //         eprintln!("Unknown type of message: {}", t);
-                //     }
-                // }
             }
             Ok(Message::Text(_)) => {
                 error!("Received text message, but expected binary data.");
@@ -133,9 +305,604 @@ async fn connection_handler(ws_stream: tokio_tungstenite::WebSocketStream<tokio:
         }
     }
 
+    // Unless the client said goodbye with a DISCONNECT or was taken over by a new connection,
+    // this connection still owns the session and its will has to be published
+    let Connection { broker, queues, client_id, .. } = connection;
+    if let Some(client_id) = client_id {
+        if let Err(e) = broker.connection_closed(client_id, queues.clone()).await {
+            error!("{}, the will cannot be published", e);
+        }
+    }
+
+    // Queued behind everything still waiting, so the writer flushes the responses before closing
+    if !writer_finished {
+        let _ = queues.sender.send(Message::Close(None)).await;
+        drop(queues);
+        let _ = writer.await;
+    }
     error!("Client disconnected.");
 }
 
+// What one connection knows about its client, shared by every transport
+struct Connection {
+    broker: BrokerHandle,
+    queues: ConnectionQueues,
+    received: PacketBuffer,
+    // Set once the client's CONNECT is accepted
+    client_id: Option<String>,
+    // Released once the CONNECT is accepted
+    pending_connect: Option<PendingConnect>,
+}
+
+impl Connection {
+    // Handles every packet completed by `data`, keeping a trailing partial packet for the next call
+    async fn handle_data(&mut self, data: &[u8]) -> ControlFlow<()> {
+        self.received.extend(data);
+        loop {
+            match self.received.next_packet() {
+                Ok(Some(packet)) => self.handle_packet(&packet).await?,
+                Ok(None) => return ControlFlow::Continue(()),
+                Err(e) => {
+                    error!("Failed to parse fixed header, closing connection: {}", e);
+                    return ControlFlow::Break(());
+                }
+            }
+        }
+    }
+
+    // Hands one complete MQTT packet to the broker and queues its response.
+    // Breaks when the connection has to be closed.
+    async fn handle_packet(&mut self, data: &[u8]) -> ControlFlow<()> {
+        let fixed_header = match MqttHeaders::parse(data) {
+            Ok(fixed_header) => fixed_header,
+            Err(e) => {
+                error!("Failed to parse fixed header, closing connection: {}", e);
+                return ControlFlow::Break(());
+            }
+        };
+        let packet_type = fixed_header.packet_type;
+        info!("Received packet of type {:?} and length {}", packet_type, fixed_header.remaining_length);
+        let incoming = match decode(data) {
+            Ok(incoming) => incoming,
+            Err(e) => {
+                error!("Malformed {:?} packet, closing connection: {}", packet_type, e);
+                return ControlFlow::Break(());
+            }
+        };
+
+        let response = match self.broker.handle_packet(incoming, data.len(), self.client_id.clone(), self.queues.clone()).await {
+            Ok(response) => response,
+            Err(e) => {
+                error!("{}, closing connection", e);
+                return ControlFlow::Break(());
+            }
+        };
+        let packet_data = match response.result {
+            Err(e) => {
+                error!("Protocol error, closing connection: {}", e);
+                return ControlFlow::Break(());
+            }
+            Ok(packet_data) => packet_data,
+        };
+        // only set for a CONNECT the broker accepted
+        if let Some(accepted_client_id) = response.client_id {
+            self.client_id = Some(accepted_client_id);
+            drop(self.pending_connect.take());
+        }
+
+        // After a DISCONNECT the client must not send anything else [MQTT-3.14.4-2],
+        // so stop reading instead of processing whatever follows on the socket.
+        if packet_type == MqttPacketType::Disconnect {
+            info!("Client sent DISCONNECT, closing connection.");
+            return ControlFlow::Break(());
+        }
+
+        if packet_data.is_empty() {
+            error!("Not a real packet data, no sending");
+            return ControlFlow::Continue(());
+        }
+        info!("packet_data: [{:?}]", packet_data);
+        let response_type = packet_data[0] >> 4;
+        if self.queues.sender.send(Message::Binary(packet_data)).await.is_err() {
+            error!("Failed to send packet of type: {:?}", response_type)
+        } else {
+            info!("Responded to Packet type: {:?}", packet_type)
+        }
+        if response.close {
+            warn!("Connection refused, closing connection.");
+            return ControlFlow::Break(());
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+// Drains the client's queues into its connection until a close frame was written or writing fails
+async fn write_outbound<S>(mut sender: S, mut priority_receiver: mpsc::Receiver<Message>, mut outbound_receiver: mpsc::Receiver<Message>)
+where
+    S: Sink<Message, Error = WsError> + Unpin,
+{
+    while let Some(outbound) = next_outbound(&mut priority_receiver, &mut outbound_receiver).await {
+        // the broker queues a close frame when another connection takes over this client id
+        let is_close = matches!(outbound, Message::Close(_));
+        if let Err(e) = sender.send(outbound).await {
+            error!("Failed to send queued message, closing connection: {}", e);
+            break;
+        }
+        if is_close {
+            info!("Close frame sent, closing connection.");
+            break;
+        }
+    }
+    let _ = sender.close().await;
+}
+
 
 
 // https://docs.solace.com/API/MQTT-311-Prtl-Conformance-Spec/MQTT%20Control%20Packets.htm
+
+
+#[cfg(test)]
+#[path = "models/test_packets.rs"]
+mod test_packets;
+
+#[cfg(test)]
+mod connection_tests {
+    use super::*;
+    use super::test_packets::{connect_packet, length_prefixed};
+    use mqtt_broker::models::{mqtt_types::ConnAckReturnCode, packets::publish::Publish};
+    use tokio::time::timeout;
+    use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+    async fn connect_client() -> WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>> {
+        connect_client_with(MqttPacketDispatcher::new().unwrap()).await
+    }
+
+    async fn connect_client_with(dispatcher: MqttPacketDispatcher) -> WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>> {
+        connect_client_to(spawn_broker(Broker::new(), dispatcher)).await
+    }
+
+    fn broker_with(config: BrokerConfig) -> BrokerHandle {
+        spawn_broker(Broker::with_config(config), MqttPacketDispatcher::new().unwrap())
+    }
+
+    fn pending_connects(config: &BrokerConfig) -> Arc<Semaphore> {
+        Arc::new(Semaphore::new(config.max_pending_connects))
+    }
+
+    async fn connect_client_to(broker: BrokerHandle) -> WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let ws_stream = accept_async(stream).await.unwrap();
+            let permit = Arc::new(Semaphore::new(1)).try_acquire_owned().unwrap();
+            let pending_connect = PendingConnect { _permit: permit, deadline: Instant::now() + BrokerConfig::default().connect_timeout };
+            connection_handler(ws_stream, broker, pending_connect, BrokerConfig::default().max_packet_size).await;
+        });
+        let (client, _) = connect_async(format!("ws://{}", addr)).await.unwrap();
+        client
+    }
+
+    // CONNECT with clean session, keep alive of 60 seconds and a QoS 0 will
+    fn connect_packet_with_will(client_id: &str, will_topic: &str, will_message: &str) -> Vec<u8> {
+        connect_packet(client_id, 0x06, &[length_prefixed(will_topic), length_prefixed(will_message)].concat())
+    }
+
+    async fn next_message(client: &mut WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>) -> Message {
+        timeout(Duration::from_secs(2), client.next()).await.unwrap().unwrap().unwrap()
+    }
+
+    // Waits for the server to end the connection, returning false if it stays open.
+    async fn is_closed_by_server(client: &mut WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>) -> bool {
+        loop {
+            match timeout(Duration::from_secs(2), client.next()).await {
+                Ok(Some(Ok(Message::Close(_)))) | Ok(Some(Err(_))) | Ok(None) => return true,
+                Ok(Some(Ok(_))) => continue,
+                Err(_) => return false,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_only_packet_closes_connection() {
+        let mut client = connect_client().await;
+        client.send(Message::Binary(vec![0x90, 0x03, 0x00, 0x01, 0x00])).await.unwrap();
+        assert!(is_closed_by_server(&mut client).await);
+    }
+
+    #[tokio::test]
+    async fn test_qos2_publish_is_delivered_once_after_pubrel() {
+        let broker = broker_with(BrokerConfig::default());
+        let mut subscriber = subscriber_to(&broker, "subscriber", "a").await;
+        let mut publisher = connect_client_to(broker).await;
+        publisher.send(Message::Binary(connect_packet("publisher", 0x02, &[]))).await.unwrap();
+        next_message(&mut publisher).await;
+
+        // QoS 2 PUBLISH to "a" with packet id 0x000A and payload "hi", then the same again with DUP set
+        let publish = vec![0x34, 0x07, 0x00, 0x01, 0x61, 0x00, 0x0A, 0x68, 0x69];
+        publisher.send(Message::Binary(publish)).await.unwrap();
+        assert_eq!(next_message(&mut publisher).await, Message::Binary(vec![0x50, 0x02, 0x00, 0x0A]));
+        let duplicate = vec![0x3C, 0x07, 0x00, 0x01, 0x61, 0x00, 0x0A, 0x68, 0x69];
+        publisher.send(Message::Binary(duplicate)).await.unwrap();
+        assert_eq!(next_message(&mut publisher).await, Message::Binary(vec![0x50, 0x02, 0x00, 0x0A]));
+        // held until released
+        assert!(timeout(Duration::from_millis(200), subscriber.next()).await.is_err());
+
+        publisher.send(Message::Binary(vec![0x62, 0x02, 0x00, 0x0A])).await.unwrap();
+        assert_eq!(next_message(&mut publisher).await, Message::Binary(vec![0x70, 0x02, 0x00, 0x0A]));
+        assert_eq!(next_message(&mut subscriber).await, Message::Binary(vec![0x30, 0x05, 0x00, 0x01, 0x61, 0x68, 0x69]));
+        assert!(timeout(Duration::from_millis(200), subscriber.next()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_packets_after_disconnect_are_not_processed() {
+        let broker = broker_with(BrokerConfig::default());
+        let mut subscriber = subscriber_to(&broker, "subscriber", "a").await;
+        let mut client = connect_client_to(broker).await;
+        client.send(Message::Binary(connect_packet("publisher", 0x02, &[]))).await.unwrap();
+        next_message(&mut client).await;
+        client.send(Message::Binary(vec![0xE0, 0x00])).await.unwrap();
+        // A PUBLISH following the DISCONNECT must be dropped with the connection
+        let _ = client.send(Message::Binary(vec![0x30, 0x06, 0x00, 0x01, 0x61, 0x68, 0x69, 0x21])).await;
+        assert!(is_closed_by_server(&mut client).await);
+        assert!(timeout(Duration::from_millis(200), subscriber.next()).await.is_err());
+    }
+    #[tokio::test]
+    async fn test_missing_handler_closes_connection() {
+        let mut dispatcher = MqttPacketDispatcher::new().unwrap();
+        dispatcher.handlers.remove(&MqttPacketType::Publish);
+        let mut client = connect_client_with(dispatcher).await;
+        client.send(Message::Binary(vec![0x30, 0x06, 0x00, 0x01, 0x61, 0x68, 0x69, 0x21])).await.unwrap();
+        assert!(is_closed_by_server(&mut client).await);
+    }
+    #[test]
+    fn test_peer_label() {
+        let addr: SocketAddr = "127.0.0.1:4242".parse().unwrap();
+        assert_eq!(peer_label(Ok(addr)), "127.0.0.1:4242");
+        let err = std::io::Error::new(std::io::ErrorKind::NotConnected, "socket is gone");
+        assert_eq!(peer_label(Err(err)), UNKNOWN_PEER);
+    }
+    #[tokio::test]
+    async fn test_accept_survives_transient_errors() {
+        let mut attempts = 0;
+        let accepted = accept_with_backoff(|| {
+            attempts += 1;
+            let attempt = attempts;
+            async move {
+                if attempt < 3 {
+                    Err(std::io::Error::other("Too many open files"))
+                } else {
+                    Ok(attempt)
+                }
+            }
+        }).await;
+        assert_eq!(accepted, 3);
+    }
+    #[tokio::test]
+    async fn test_invalid_packet_type_closes_connection() {
+        let mut client = connect_client().await;
+        client.send(Message::Binary(vec![0xF0, 0x00])).await.unwrap();
+        assert!(is_closed_by_server(&mut client).await);
+    }
+
+    #[tokio::test]
+    async fn test_empty_frame_is_ignored() {
+        let mut client = connect_client().await;
+        client.send(Message::Binary(vec![])).await.unwrap();
+        client.send(Message::Binary(connect_packet("c1", 0x02, &[]))).await.unwrap();
+        assert_eq!(next_message(&mut client).await, Message::Binary(vec![0x20, 0x02, 0x00, 0x00]));
+    }
+
+    #[tokio::test]
+    async fn test_packets_spanning_and_sharing_frames() {
+        let mut client = connect_client().await;
+        let connect = connect_packet("c1", 0x02, &[]);
+        // the CONNECT split after its fixed header, the second frame also carrying a PINGREQ
+        client.send(Message::Binary(connect[..2].to_vec())).await.unwrap();
+        client.send(Message::Binary([&connect[2..], &[0xC0, 0x00][..]].concat())).await.unwrap();
+        assert_eq!(next_message(&mut client).await, Message::Binary(vec![0x20, 0x02, 0x00, 0x00]));
+        assert_eq!(next_message(&mut client).await, Message::Binary(vec![0xD0, 0x00]));
+    }
+    #[tokio::test]
+    async fn test_rejected_connect_closes_connection_after_connack() {
+        let mut client = connect_client().await;
+        let client_id = "c".repeat(300); // longer than the default client id cap
+        client.send(Message::Binary(connect_packet(&client_id, 0x02, &[]))).await.unwrap();
+        let connack = timeout(Duration::from_secs(2), client.next()).await.unwrap().unwrap().unwrap();
+        assert_eq!(connack, Message::Binary(vec![0x20, 0x02, 0x00, ConnAckReturnCode::IdentifierRejected.to_u8()]));
+        assert!(is_closed_by_server(&mut client).await);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_packets_do_not_keep_client_alive() {
+        let broker = broker_with(BrokerConfig::default());
+        let mut client = connect_client_to(broker.clone()).await;
+        // without clean session, so the session outlives the connection and its last_seen can be checked
+        client.send(Message::Binary(connect_packet("test", 0x00, &[]))).await.unwrap();
+        timeout(Duration::from_secs(2), client.next()).await.unwrap().unwrap().unwrap();
+        let last_seen = broker.call(|broker| broker.get_client("test").unwrap().last_seen).await.unwrap();
+
+        sleep(Duration::from_millis(20)).await;
+        // SUBSCRIBE with packet id 0 is invalid, the first one must close the connection
+        for _ in 0..3 {
+            let _ = client.send(Message::Binary(vec![0x82, 0x06, 0x00, 0x00, 0x00, 0x01, 0x61, 0x00])).await;
+        }
+        assert!(is_closed_by_server(&mut client).await);
+        assert_eq!(broker.call(|broker| broker.get_client("test").unwrap().last_seen).await.unwrap(), last_seen);
+    }
+
+    #[tokio::test]
+    async fn test_takeover_closes_old_connection_without_will() {
+        let broker = broker_with(BrokerConfig::default());
+        let mut subscriber = connect_client_to(broker.clone()).await;
+        subscriber.send(Message::Binary(connect_packet("sub", 0x02, &[]))).await.unwrap();
+        next_message(&mut subscriber).await;
+        // SUBSCRIBE packet id 1 to "will/c1" with QoS 0
+        let mut subscribe = vec![0x82, 0x0C, 0x00, 0x01];
+        subscribe.extend(length_prefixed("will/c1"));
+        subscribe.push(0x00);
+        subscriber.send(Message::Binary(subscribe)).await.unwrap();
+        assert_eq!(next_message(&mut subscriber).await, Message::Binary(vec![0x90, 0x03, 0x00, 0x01, 0x00]));
+
+        let mut old = connect_client_to(broker.clone()).await;
+        old.send(Message::Binary(connect_packet_with_will("c1", "will/c1", "bye"))).await.unwrap();
+        assert_eq!(next_message(&mut old).await, Message::Binary(vec![0x20, 0x02, 0x00, 0x00]));
+
+        let mut new = connect_client_to(broker.clone()).await;
+        new.send(Message::Binary(connect_packet("c1", 0x02, &[]))).await.unwrap();
+        assert_eq!(next_message(&mut new).await, Message::Binary(vec![0x20, 0x02, 0x00, 0x00]));
+
+        assert!(is_closed_by_server(&mut old).await);
+        assert!(timeout(Duration::from_millis(200), subscriber.next()).await.is_err());
+        assert!(broker.call(|broker| broker.is_client_connected("c1")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_empty_client_id_is_assigned_one() {
+        let broker = broker_with(BrokerConfig::default());
+        let mut client = connect_client_to(broker.clone()).await;
+        client.send(Message::Binary(connect_packet("", 0x02, &[]))).await.unwrap();
+        assert_eq!(next_message(&mut client).await, Message::Binary(vec![0x20, 0x02, 0x00, 0x00]));
+        // the connection now has a client id, so a PINGREQ is answered instead of closing it
+        client.send(Message::Binary(vec![0xC0, 0x00])).await.unwrap();
+        assert_eq!(next_message(&mut client).await, Message::Binary(vec![0xD0, 0x00]));
+        assert_eq!(broker.call(|broker| broker.stats().connected_clients).await.unwrap(), 1);
+    }
+
+    // Connects a client subscribed to the topic with QoS 0 and waits for its CONNACK and SUBACK
+    async fn subscriber_to(broker: &BrokerHandle, client_id: &str, topic: &str) -> WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>> {
+        let mut subscriber = connect_client_to(broker.clone()).await;
+        subscriber.send(Message::Binary(connect_packet(client_id, 0x02, &[]))).await.unwrap();
+        next_message(&mut subscriber).await;
+        let mut body = vec![0x00, 0x01];
+        body.extend(length_prefixed(topic));
+        body.push(0x00);
+        let mut subscribe = MqttHeaders::new(MqttPacketType::Subscribe, 0b0010, body.len() as u32).to_bytes();
+        subscribe.extend(body);
+        subscriber.send(Message::Binary(subscribe)).await.unwrap();
+        next_message(&mut subscriber).await;
+        subscriber
+    }
+
+    #[tokio::test]
+    async fn test_will_is_published_when_connection_drops() {
+        let broker = broker_with(BrokerConfig::default());
+        let mut subscriber = subscriber_to(&broker, "sub", "will/c1").await;
+        let mut client = connect_client_to(broker.clone()).await;
+        client.send(Message::Binary(connect_packet_with_will("c1", "will/c1", "bye"))).await.unwrap();
+        next_message(&mut client).await;
+
+        drop(client);
+        let mut will = vec![0x30, 0x0C];
+        will.extend(length_prefixed("will/c1"));
+        will.extend(b"bye");
+        assert_eq!(next_message(&mut subscriber).await, Message::Binary(will));
+        assert!(!broker.call(|broker| broker.is_client_connected("c1")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_will_is_not_published_after_disconnect() {
+        let broker = broker_with(BrokerConfig::default());
+        let mut subscriber = subscriber_to(&broker, "sub", "will/c1").await;
+        let mut client = connect_client_to(broker.clone()).await;
+        client.send(Message::Binary(connect_packet_with_will("c1", "will/c1", "bye"))).await.unwrap();
+        next_message(&mut client).await;
+
+        client.send(Message::Binary(vec![0xE0, 0x00])).await.unwrap();
+        assert!(is_closed_by_server(&mut client).await);
+        assert!(timeout(Duration::from_millis(200), subscriber.next()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reaper_drops_silent_client() {
+        let config = BrokerConfig {
+            keep_alive_check_interval: Duration::from_millis(10),
+            keep_alive_grace_factor: 0.001, // 60ms for the keep alive of 60 seconds
+            ..BrokerConfig::default()
+        };
+        let broker = broker_with(config);
+        spawn(keep_alive_reaper(broker.clone()));
+        let mut client = connect_client_to(broker.clone()).await;
+        client.send(Message::Binary(connect_packet("silent", 0x02, &[]))).await.unwrap();
+        next_message(&mut client).await;
+
+        assert!(is_closed_by_server(&mut client).await);
+        assert!(!broker.call(|broker| broker.is_client_connected("silent")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_pending_connects_are_limited() {
+        let config = BrokerConfig { max_pending_connects: 1, ..BrokerConfig::default() };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        spawn(serve(listener, broker_with(config.clone()), Transport::WebSocket, pending_connects(&config)));
+
+        let mut pending = connect_async(&url).await.unwrap().0;
+        assert!(connect_async(&url).await.is_err());
+
+        pending.send(Message::Binary(connect_packet("first", 0x02, &[]))).await.unwrap();
+        next_message(&mut pending).await;
+        assert!(connect_async(&url).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_pending_connects_are_shared_between_listeners() {
+        let config = BrokerConfig { max_pending_connects: 1, ..BrokerConfig::default() };
+        let broker = broker_with(config.clone());
+        let pending_connects = pending_connects(&config);
+        let websocket_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", websocket_listener.local_addr().unwrap());
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tcp_addr = tcp_listener.local_addr().unwrap();
+        spawn(serve(websocket_listener, broker.clone(), Transport::WebSocket, Arc::clone(&pending_connects)));
+        spawn(serve(tcp_listener, broker, Transport::Mqtt, pending_connects));
+
+        let _pending = connect_async(&url).await.unwrap().0;
+        // the WebSocket connection holds the only permit, so the TCP one is closed without an answer
+        let mut raw = TcpStream::connect(tcp_addr).await.unwrap();
+        let _ = raw.write_all(&connect_packet("raw", 0x02, &[])).await;
+        let mut connack = [0; 4];
+        assert!(timeout(Duration::from_secs(2), raw.read_exact(&mut connack)).await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rejected_connect_does_not_hijack_session() {
+        let broker = broker_with(BrokerConfig::default());
+        let mut watcher = subscriber_to(&broker, "watcher", "will/#").await;
+        let mut victim = connect_client_to(broker.clone()).await;
+        victim.send(Message::Binary(connect_packet_with_will("victim", "will/victim", "bye"))).await.unwrap();
+        next_message(&mut victim).await;
+
+        // the victim's client id with an unsupported protocol level
+        let mut attacker = connect_client_to(broker.clone()).await;
+        let mut rejected = connect_packet("victim", 0x02, &[]);
+        rejected[8] = 0x03;
+        attacker.send(Message::Binary(rejected)).await.unwrap();
+        assert_eq!(next_message(&mut attacker).await, Message::Binary(vec![0x20, 0x02, 0x00, 0x01]));
+        assert!(is_closed_by_server(&mut attacker).await);
+
+        assert!(broker.call(|broker| broker.is_client_connected("victim")).await.unwrap());
+        assert!(timeout(Duration::from_millis(200), watcher.next()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_retained_messages_are_sent_before_suback() {
+        let broker = broker_with(BrokerConfig::default());
+        broker.call(|broker| broker.retain("a/b", b"kept")).await.unwrap();
+        let mut client = connect_client_to(broker).await;
+        client.send(Message::Binary(connect_packet("sub", 0x02, &[]))).await.unwrap();
+        next_message(&mut client).await;
+
+        // SUBSCRIBE packet id 1 to "a/#" with QoS 0
+        client.send(Message::Binary(vec![0x82, 0x08, 0x00, 0x01, 0x00, 0x03, 0x61, 0x2F, 0x23, 0x00])).await.unwrap();
+        assert_eq!(next_message(&mut client).await, Message::Binary(Publish::for_retained_delivery("a/b", b"kept").to_bytes()));
+        assert_eq!(next_message(&mut client).await, Message::Binary(vec![0x90, 0x03, 0x00, 0x01, 0x00]));
+    }
+
+    #[tokio::test]
+    async fn test_broker_pushes_to_idle_client() {
+        let broker = broker_with(BrokerConfig::default());
+        let mut client = connect_client_to(broker.clone()).await;
+        client.send(Message::Binary(connect_packet("idle", 0x02, &[]))).await.unwrap();
+        next_message(&mut client).await;
+
+        let publish = Publish::for_delivery("a/b", b"pushed").to_bytes();
+        let queued = publish.clone();
+        assert!(broker.call(move |broker| broker.send_to("idle", &queued)).await.unwrap());
+        assert_eq!(next_message(&mut client).await, Message::Binary(publish));
+    }
+
+    #[tokio::test]
+    async fn test_one_port_serves_raw_mqtt_and_websocket() {
+        let config = BrokerConfig { detect_transport: true, ..BrokerConfig::default() };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn(serve(listener, broker_with(config.clone()), Transport::WebSocket, pending_connects(&config)));
+
+        let mut raw = TcpStream::connect(addr).await.unwrap();
+        raw.write_all(&connect_packet("raw", 0x02, &[])).await.unwrap();
+        let mut connack = [0; 4];
+        timeout(Duration::from_secs(2), raw.read_exact(&mut connack)).await.unwrap().unwrap();
+        assert_eq!(connack, [0x20, 0x02, 0x00, 0x00]);
+
+        let mut websocket = connect_async(format!("ws://{}", addr)).await.unwrap().0;
+        websocket.send(Message::Binary(connect_packet("websocket", 0x02, &[]))).await.unwrap();
+        assert_eq!(next_message(&mut websocket).await, Message::Binary(vec![0x20, 0x02, 0x00, 0x00]));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_client_receives_websocket_publish() {
+        let broker = broker_with(BrokerConfig::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn(serve(listener, broker.clone(), Transport::Mqtt, pending_connects(&BrokerConfig::default())));
+
+        let mut subscriber = TcpStream::connect(addr).await.unwrap();
+        subscriber.write_all(&connect_packet("tcp", 0x02, &[])).await.unwrap();
+        // SUBSCRIBE to "a/b" at QoS 0 with packet id 1
+        subscriber.write_all(&[0x82, 0x08, 0x00, 0x01, 0x00, 0x03, 0x61, 0x2F, 0x62, 0x00]).await.unwrap();
+        // both responses may arrive in one read, the stream has no message boundaries
+        let mut responses = [0; 9];
+        timeout(Duration::from_secs(2), subscriber.read_exact(&mut responses)).await.unwrap().unwrap();
+        assert_eq!(responses, [0x20, 0x02, 0x00, 0x00, 0x90, 0x03, 0x00, 0x01, 0x00]);
+
+        let mut publisher = connect_client_to(broker).await;
+        publisher.send(Message::Binary(connect_packet("websocket", 0x02, &[]))).await.unwrap();
+        next_message(&mut publisher).await;
+        let publish = Publish::for_delivery("a/b", b"hi").to_bytes();
+        publisher.send(Message::Binary(publish.clone())).await.unwrap();
+
+        let mut delivered = vec![0; publish.len()];
+        timeout(Duration::from_secs(2), subscriber.read_exact(&mut delivered)).await.unwrap().unwrap();
+        assert_eq!(delivered, publish);
+    }
+
+    #[tokio::test]
+    async fn test_raw_packets_are_split_on_remaining_length() {
+        let mut input = connect_packet("raw", 0x02, &[]);
+        input.extend([0xC0, 0x00]);
+        let mut reader = &input[..];
+        assert_eq!(read_packet(&mut reader, 1024).await.unwrap(), Some(connect_packet("raw", 0x02, &[])));
+        assert_eq!(read_packet(&mut reader, 1024).await.unwrap(), Some(vec![0xC0, 0x00]));
+        assert_eq!(read_packet(&mut reader, 1024).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_raw_packet_over_max_size_is_rejected_from_its_header() {
+        // PUBLISH announcing the largest remaining length there is, without any body
+        let mut reader = &[0x30, 0xFF, 0xFF, 0xFF, 0x7F][..];
+        let error = read_packet(&mut reader, 1024).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_silent_websocket_is_closed_after_connect_timeout() {
+        let config = BrokerConfig { connect_timeout: Duration::from_millis(300), ..BrokerConfig::default() };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        spawn(serve(listener, broker_with(config.clone()), Transport::WebSocket, pending_connects(&config)));
+
+        let mut client = connect_async(&url).await.unwrap().0;
+        let started = Instant::now();
+        assert!(is_closed_by_server(&mut client).await);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn test_priority_message_overtakes_backlog() {
+        let config = BrokerConfig { priority_topics: vec!["ctrl/#".to_string()], ..BrokerConfig::default() };
+        let broker = broker_with(config);
+        let mut client = subscriber_to(&broker, "sub", "#").await;
+        broker
+            .call(|broker| {
+                for _ in 0..5 {
+                    broker.route("bulk/data", &Publish::for_delivery("bulk/data", b"bulk").to_bytes());
+                }
+                broker.route("ctrl/stop", &Publish::for_delivery("ctrl/stop", b"stop").to_bytes());
+            })
+            .await
+            .unwrap();
+        assert_eq!(next_message(&mut client).await, Message::Binary(Publish::for_delivery("ctrl/stop", b"stop").to_bytes()));
+        assert_eq!(next_message(&mut client).await, Message::Binary(Publish::for_delivery("bulk/data", b"bulk").to_bytes()));
+    }
+}
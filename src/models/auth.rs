@@ -0,0 +1,17 @@
+use std::fmt;
+
+// Decides whether a CONNECT may proceed, called before the client is registered.
+// The error is the CONNACK return code sent back, 0x04 for bad credentials or 0x05 for not authorized.
+pub trait Authenticator: Send + Sync + fmt::Debug {
+    fn authenticate(&self, client_id: &str, username: Option<&str>, password: Option<&str>) -> Result<(), u8>;
+}
+
+// Lets every client in, the broker's behaviour until an authenticator is set
+#[derive(Debug, Default)]
+pub struct AllowAll;
+
+impl Authenticator for AllowAll {
+    fn authenticate(&self, _client_id: &str, _username: Option<&str>, _password: Option<&str>) -> Result<(), u8> {
+        Ok(())
+    }
+}
@@ -1,22 +1,101 @@
-use core::panic;
-use std::{collections::{HashMap, HashSet}, time::{Duration, SystemTime}};
+use std::{collections::{hash_map::RandomState, HashMap, HashSet, VecDeque}, hash::{BuildHasher, Hasher}, sync::Arc, time::{Duration, Instant, SystemTime}};
 
-use log::info;
+use log::{info, warn};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use crate::models::auth::{AllowAll, Authenticator};
+use crate::models::config::{BrokerConfig, FanOutPolicy};
+use crate::models::metrics::MetricSink;
+use crate::models::packets::{connect::Will, publish::Publish};
+use crate::models::topic::topic_matches;
+
+// How many random ids are tried for a client connecting without one before giving up
+const MAX_CLIENT_ID_ATTEMPTS: usize = 100;
 
 #[derive(Debug)]
-enum ConnectionStatus {
+pub enum ConnectionStatus {
     Connected,
     Disconnected,
     AwaitingReconnect,
 }
 
+// Hands out packet identifiers for outbound QoS > 0 packets.
+// Valid ids are 1..=65535 [MQTT-2.3.1-1]; 0 is never returned and the counter wraps from 65535 back to 1,
+// skipping any id that is still in flight.
+#[derive(Debug)]
+pub struct PacketIdAllocator {
+    next_id: u16,
+    in_use: HashSet<u16>,
+}
+
+impl PacketIdAllocator {
+    pub fn new() -> Self {
+        PacketIdAllocator {
+            next_id: 1,
+            in_use: HashSet::new(),
+        }
+    }
+
+    // Returns None when all 65535 ids are in flight
+    pub fn allocate(&mut self) -> Option<u16> {
+        if self.in_use.len() == u16::MAX as usize {
+            return None;
+        }
+        loop {
+            let id = self.next_id;
+            self.next_id = if id == u16::MAX { 1 } else { id + 1 };
+            if self.in_use.insert(id) {
+                return Some(id);
+            }
+        }
+    }
+
+    pub fn release(&mut self, id: u16) -> bool {
+        self.in_use.remove(&id)
+    }
+
+    pub fn in_use(&self) -> usize {
+        self.in_use.len()
+    }
+}
+
+impl Default for PacketIdAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Debug)]
-struct ClientState {
-    client_id: String,
-    connected_status: ConnectionStatus,
-    subscriptions: HashSet<String>,
-    last_seen: SystemTime,
-    keep_alive: Duration,
+pub struct ClientState {
+    pub client_id: String,
+    pub connected_status: ConnectionStatus,
+    pub subscriptions: HashSet<String>,
+    pub last_seen: SystemTime,
+    pub keep_alive: Duration,
+    pub packet_ids: PacketIdAllocator,
+    // QoS 2 messages received from the client by packet id, held back until its PUBREL
+    pub awaiting_release: HashMap<u16, HeldMessage>,
+    pub clean_session: bool,
+    // Published when the connection is lost without a DISCONNECT
+    pub will: Option<Will>,
+    // Outbound queue of the client's connection, set once its CONNECT is accepted
+    pub sender: Option<mpsc::Sender<Message>>,
+    // Outbound queue for messages on priority topics, drained by the connection before `sender`
+    pub priority_sender: Option<mpsc::Sender<Message>>,
+    // Set by `Broker::pause_client`. Messages for the client then wait in `paused_messages`, with whether
+    // they are high priority, instead of reaching its connection.
+    pub paused: bool,
+    pub paused_messages: VecDeque<(Vec<u8>, bool)>,
+}
+
+// A QoS 2 message waiting for its PUBREL. Nothing about it is visible to other clients before then,
+// not even when it is retained.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeldMessage {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
 }
 
 impl ClientState {
@@ -27,6 +106,14 @@ impl ClientState {
             subscriptions: HashSet::new(),
             last_seen: SystemTime::now(),
             keep_alive,
+            packet_ids: PacketIdAllocator::new(),
+            awaiting_release: HashMap::new(),
+            clean_session: true,
+            will: None,
+            sender: None,
+            priority_sender: None,
+            paused: false,
+            paused_messages: VecDeque::new(),
         }
     }
 
@@ -34,28 +121,172 @@ impl ClientState {
         self.last_seen = SystemTime::now();
     }
     
-    pub fn is_alive(&self) -> bool {
-        self.last_seen.elapsed().unwrap_or(Duration::ZERO) <= self.keep_alive
+    pub fn is_connected(&self) -> bool {
+        matches!(self.connected_status, ConnectionStatus::Connected)
+    }
+
+    // A keep alive of zero turns the mechanism off [MQTT-3.1.2-23]
+    pub fn is_alive(&self, grace_factor: f64) -> bool {
+        self.keep_alive.is_zero() || self.last_seen.elapsed().unwrap_or(Duration::ZERO) <= self.keep_alive.mul_f64(grace_factor)
+    }
+
+    // Messages queued on the client's connection that it has not written out yet. A depth that keeps
+    // growing points at a slow consumer, which starts losing messages once its queues are full.
+    pub fn queue_depth(&self) -> usize {
+        let queued: usize = [&self.sender, &self.priority_sender]
+            .into_iter()
+            .flatten()
+            .map(|sender| sender.max_capacity() - sender.capacity())
+            .sum();
+        queued + self.paused_messages.len()
     }
 }
 
+// Point in time snapshot of the broker counters, taken in one call so the values are consistent with each other
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BrokerStats {
+    pub connected_clients: usize,
+    pub total_connected: u64,
+    pub total_subscriptions: usize,
+    pub retained_messages: usize,
+    pub messages_received: u64,
+    pub messages_sent: u64,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    // Publishes that matched more subscribers than `BrokerConfig::max_fan_out`
+    pub excessive_fan_outs: u64,
+    pub uptime: Duration,
+}
+
 #[derive(Debug)]
 pub struct Broker {
     clients: HashMap<String, ClientState>,
+    config: BrokerConfig,
+    // Last retained message per topic, handed to every new matching subscription [MQTT-3.3.1-6]
+    retained: HashMap<String, Vec<u8>>,
+    started_at: Instant,
+    total_connected: u64,
+    messages_received: u64,
+    messages_sent: u64,
+    bytes_received: u64,
+    bytes_sent: u64,
+    excessive_fan_outs: u64,
+    // Optional push target for the counters above
+    metric_sink: Option<Arc<dyn MetricSink>>,
+    // Checks the credentials of every CONNECT, lets everyone in by default
+    authenticator: Box<dyn Authenticator>,
 }
 
+impl Default for Broker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Broker {
     pub fn new() -> Self {
+        Self::with_config(BrokerConfig::default())
+    }
+
+    pub fn with_config(config: BrokerConfig) -> Self {
         Broker {
             clients: HashMap::new(),
+            config,
+            retained: HashMap::new(),
+            started_at: Instant::now(),
+            total_connected: 0,
+            messages_received: 0,
+            messages_sent: 0,
+            bytes_received: 0,
+            bytes_sent: 0,
+            excessive_fan_outs: 0,
+            metric_sink: None,
+            authenticator: Box::new(AllowAll),
+        }
+    }
+
+    pub fn config(&self) -> &BrokerConfig {
+        &self.config
+    }
+
+    pub fn set_authenticator(&mut self, authenticator: Box<dyn Authenticator>) {
+        self.authenticator = authenticator;
+    }
+
+    pub fn authenticate(&self, client_id: &str, username: Option<&str>, password: Option<&str>) -> Result<(), u8> {
+        self.authenticator.authenticate(client_id, username, password)
+    }
+
+    pub fn set_metric_sink(&mut self, metric_sink: Arc<dyn MetricSink>) {
+        self.metric_sink = Some(metric_sink);
+    }
+
+    fn record_metric(&self, record: impl FnOnce(&dyn MetricSink)) {
+        if let Some(metric_sink) = &self.metric_sink {
+            record(metric_sink.as_ref());
         }
     }
 
+    // Takes no outbound sender. Clients are added by the CONNECT handler, which never sees the connection's queues,
+    // so the broker task attaches them with `set_client_sender` after checking the CONNACK accepted the client.
     pub fn add_client(&mut self, client_id: &str, keep_alive: u16) {
         let keep_alive_duration = Duration::from_secs(keep_alive as u64);
         let client = ClientState::new(client_id, keep_alive_duration);
         self.clients.insert(client_id.to_string(), client);
+        self.record_connect();
+    }
+
+    // Registers a client whose CONNECT was accepted and returns whether a stored session was present.
+    // A clean session always starts over [MQTT-3.1.2-6], otherwise a stored session is resumed with its
+    // subscriptions [MQTT-3.1.2-4], so the session present flag is only set for a resumed session [MQTT-3.2.2-1], [MQTT-3.2.2-2]
+    pub fn connect_client(&mut self, client_id: &str, keep_alive: u16, clean_session: bool) -> bool {
+        let resumed = if clean_session { None } else { self.clients.get_mut(client_id) };
+        let Some(client) = resumed else {
+            self.add_client(client_id, keep_alive);
+            if let Some(client) = self.clients.get_mut(client_id) {
+                client.clean_session = clean_session;
+            }
+            return false;
+        };
+        client.connected_status = ConnectionStatus::Connected;
+        client.keep_alive = Duration::from_secs(keep_alive as u64);
+        client.update_last_seen();
+        self.record_connect();
+        true
+    }
+
+    fn record_connect(&mut self) {
+        self.total_connected += 1;
+        let connected_clients = self.stats().connected_clients as u64;
+        self.record_metric(|sink| {
+            sink.record_counter("clients.connects", 1);
+            sink.record_gauge("clients.connected", connected_clients);
+        });
+    }
+
+    // Generates an id for a client that connected with an empty one, not used by any current client.
+    // Returns `None` when no free id turned up within `MAX_CLIENT_ID_ATTEMPTS`, as happens once
+    // a small id space is nearly used up.
+    pub fn generate_client_id(&self) -> Option<String> {
+        let mut charset: Vec<char> = self.config.generated_client_id_charset.chars().collect();
+        if charset.is_empty() {
+            charset = BrokerConfig::default().generated_client_id_charset.chars().collect();
+        }
+        let length = self.config.generated_client_id_length.max(1);
+        for _ in 0..MAX_CLIENT_ID_ATTEMPTS {
+            // a freshly keyed hasher per id is good enough to spread ids, they only need to be unique
+            let mut hasher = RandomState::new().build_hasher();
+            let mut client_id = self.config.generated_client_id_prefix.clone();
+            for position in 0..length {
+                hasher.write_usize(position);
+                let index = hasher.finish() as usize % charset.len();
+                client_id.push(charset[index]);
+            }
+            if !self.clients.contains_key(&client_id) {
+                return Some(client_id);
+            }
+        }
+        None
     }
 
     pub fn remove_client(&mut self, client_id: &str) -> String {
@@ -70,11 +301,753 @@ impl Broker {
         }
     }
 
+    // Attaches the connection's outbound queue, returns false when the client is not registered
+    pub fn set_client_sender(&mut self, client_id: &str, sender: mpsc::Sender<Message>) -> bool {
+        match self.clients.get_mut(client_id) {
+            Some(client) => {
+                client.sender = Some(sender);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_client_priority_sender(&mut self, client_id: &str, sender: mpsc::Sender<Message>) -> bool {
+        match self.clients.get_mut(client_id) {
+            Some(client) => {
+                client.priority_sender = Some(sender);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Returns false when the client is unknown or already subscribed to the filter
+    pub fn add_subscription(&mut self, client_id: &str, topic_filter: &str) -> bool {
+        match self.clients.get_mut(client_id) {
+            Some(client) => client.subscriptions.insert(topic_filter.to_string()),
+            None => false,
+        }
+    }
+
+    // Drops every subscription of the client, so nothing is routed to it any more, and returns how many there were.
+    // Subscriptions only live on the client, so clearing them leaves nothing behind for routing to find.
+    pub fn remove_all_subscriptions(&mut self, client_id: &str) -> usize {
+        match self.clients.get_mut(client_id) {
+            Some(client) => {
+                let removed = client.subscriptions.len();
+                client.subscriptions.clear();
+                removed
+            }
+            None => 0,
+        }
+    }
+
+    // Holds a QoS 2 message until the client releases it. Returns false when the packet id is already held,
+    // a retransmitted PUBLISH must not replace or duplicate the message [MQTT-4.3.3-2]
+    pub fn hold_for_release(&mut self, client_id: &str, packet_id: u16, message: HeldMessage) -> bool {
+        let Some(client) = self.clients.get_mut(client_id) else {
+            return false;
+        };
+        if client.awaiting_release.contains_key(&packet_id) {
+            return false;
+        }
+        client.awaiting_release.insert(packet_id, message);
+        true
+    }
+
+    pub fn release(&mut self, client_id: &str, packet_id: u16) -> Option<HeldMessage> {
+        self.clients.get_mut(client_id)?.awaiting_release.remove(&packet_id)
+    }
+
+    // Whether a publish to the topic would reach anyone, so a publisher can skip producing it
+    pub fn has_subscribers(&self, topic: &str) -> bool {
+        self.clients
+            .values()
+            .any(|client| client.subscriptions.iter().any(|filter| topic_matches(filter, topic)))
+    }
+
+    // Queues the packet on the connection of every client with a subscription matching the topic and returns
+    // how many clients it was queued for. A client whose queue is full misses the message.
+    // Routing happens on the broker task one command at a time, so a client receives messages in the order the broker received
+    // them, except that messages on a configured priority topic overtake the ones still queued.
+    pub fn route(&mut self, topic: &str, packet: &[u8]) -> usize {
+        let mut subscribers: Vec<String> = self
+            .clients
+            .values()
+            .filter(|client| client.subscriptions.iter().any(|filter| topic_matches(filter, topic)))
+            .map(|client| client.client_id.clone())
+            .collect();
+        if let Some(max_fan_out) = self.config.max_fan_out {
+            if subscribers.len() > max_fan_out {
+                warn!("Publish to [{}] matches {} subscribers, more than the limit of {}", topic, subscribers.len(), max_fan_out);
+                self.excessive_fan_outs += 1;
+                self.record_metric(|sink| sink.record_counter("messages.excessive_fan_out", 1));
+                if self.config.fan_out_policy == FanOutPolicy::Truncate {
+                    subscribers.truncate(max_fan_out);
+                }
+            }
+        }
+        let high_priority = self.config.priority_topics.iter().any(|filter| topic_matches(filter, topic));
+        let delivered = subscribers.iter().filter(|client_id| self.queue(client_id, packet, high_priority)).count();
+        self.record_metric(|sink| {
+            sink.record_counter("messages.published", 1);
+            sink.record_histogram("messages.fan_out", delivered as f64);
+        });
+        delivered
+    }
+
+    // Queues the packet on the client's connection. Returns false when the client has no connection
+    // or its queue is full.
+    pub fn send_to(&mut self, client_id: &str, packet: &[u8]) -> bool {
+        self.queue(client_id, packet, false)
+    }
+
+    // Stops handing messages to the client's connection without disconnecting it, e.g. to quiesce a misbehaving client.
+    // Messages are kept in the meantime, up to as many as its connection's queue holds, the ones beyond that are dropped.
+    pub fn pause_client(&mut self, client_id: &str) -> bool {
+        match self.clients.get_mut(client_id) {
+            Some(client) => {
+                client.paused = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Hands the messages kept while the client was paused to its connection, in the order they arrived
+    pub fn resume_client(&mut self, client_id: &str) -> bool {
+        let Some(client) = self.clients.get_mut(client_id) else {
+            return false;
+        };
+        client.paused = false;
+        let paused_messages = std::mem::take(&mut client.paused_messages);
+        for (packet, high_priority) in paused_messages {
+            self.queue(client_id, &packet, high_priority);
+        }
+        true
+    }
+
+    // A client without a priority queue gets its priority messages on the regular one
+    fn queue(&mut self, client_id: &str, packet: &[u8], high_priority: bool) -> bool {
+        let Some(client) = self.clients.get_mut(client_id) else {
+            return false;
+        };
+        if client.paused {
+            let cap = client.sender.as_ref().map_or(0, mpsc::Sender::max_capacity);
+            if client.paused_messages.len() >= cap {
+                warn!("Dropping message for paused client [{}], {} messages are already kept", client_id, cap);
+                return false;
+            }
+            client.paused_messages.push_back((packet.to_vec(), high_priority));
+            return true;
+        }
+        let sender = match (high_priority, &client.priority_sender) {
+            (true, Some(priority_sender)) => priority_sender.clone(),
+            _ => match &client.sender {
+                Some(sender) => sender.clone(),
+                None => return false,
+            },
+        };
+        match sender.try_send(Message::Binary(packet.to_vec())) {
+            Ok(()) => {
+                self.record_sent(packet.len());
+                true
+            }
+            Err(e) => {
+                warn!("Dropping message for client [{}]: {}", client_id, e);
+                false
+            }
+        }
+    }
+
+    // A retained message replaces the previous one of its topic, an empty payload removes it [MQTT-3.3.1-10]
+    pub fn retain(&mut self, topic: &str, payload: &[u8]) {
+        if payload.is_empty() {
+            self.retained.remove(topic);
+        } else {
+            self.retained.insert(topic.to_string(), payload.to_vec());
+        }
+    }
+
+    pub fn retained_message(&self, topic: &str) -> Option<&[u8]> {
+        self.retained.get(topic).map(Vec::as_slice)
+    }
+
+    pub fn retained_matching(&self, topic_filter: &str) -> Vec<(String, Vec<u8>)> {
+        self.retained
+            .iter()
+            .filter(|(topic, _)| topic_matches(topic_filter, topic))
+            .map(|(topic, payload)| (topic.clone(), payload.clone()))
+            .collect()
+    }
+
     pub fn get_client(&self, client_id: &str) -> Option<&ClientState> {
         self.clients.get(client_id)
     }
 
+    pub fn get_client_mut(&mut self, client_id: &str) -> Option<&mut ClientState> {
+        self.clients.get_mut(client_id)
+    }
+
+    pub fn queue_depth(&self, client_id: &str) -> Option<usize> {
+        self.clients.get(client_id).map(ClientState::queue_depth)
+    }
+
     pub fn is_client_connected(&self, client_id: &str) -> bool {
-        self.clients.contains_key(client_id)
+        self.clients.get(client_id).is_some_and(ClientState::is_connected)
+    }
+
+    // The connection of the client ended without a DISCONNECT, so its will is published as if the client
+    // had published it [MQTT-3.1.2-8] and the session ends as on DISCONNECT. The will is taken first so it
+    // can only fire once.
+    pub fn connection_lost(&mut self, client_id: &str) -> bool {
+        let Some(client) = self.clients.get_mut(client_id) else {
+            return false;
+        };
+        let will = client.will.take();
+        self.disconnect_client(client_id);
+        if let Some(will) = will {
+            self.publish_will(client_id, &will);
+        }
+        true
+    }
+
+    fn publish_will(&mut self, client_id: &str, will: &Will) {
+        let payload = will.message.as_bytes();
+        if will.retain {
+            self.retain(&will.topic, payload);
+        }
+        let delivered = self.route(&will.topic, &Publish::for_delivery(&will.topic, payload).to_bytes());
+        info!("Published will of [{}] on [{}] to {} subscribers", client_id, will.topic, delivered);
+    }
+
+    // Drops every connected client that outlived its keep alive: the connection is closed, the will published
+    // and the session ended. Returns the ids of the dropped clients.
+    pub fn reap_expired_clients(&mut self) -> Vec<String> {
+        let grace_factor = self.config.keep_alive_grace_factor;
+        let expired: Vec<String> = self
+            .clients
+            .values()
+            .filter(|client| client.is_connected() && !client.is_alive(grace_factor))
+            .map(|client| client.client_id.clone())
+            .collect();
+        for client_id in &expired {
+            warn!("Client [{}] exceeded its keep alive, closing the connection", client_id);
+            if let Some(sender) = self.clients.get_mut(client_id).and_then(|client| client.sender.take()) {
+                let _ = sender.try_send(Message::Close(None));
+            }
+            self.connection_lost(client_id);
+        }
+        expired
+    }
+
+    // A new connection with the same client id replaces the existing one [MQTT-3.1.4-2].
+    // The old connection is closed without publishing its will, it is being replaced rather than lost.
+    // A persistent session stays behind for the new connection to resume.
+    pub fn take_over_client(&mut self, client_id: &str) -> bool {
+        let Some(client) = self.clients.get_mut(client_id) else {
+            return false;
+        };
+        client.will = None;
+        client.priority_sender = None;
+        if let Some(sender) = client.sender.take() {
+            let _ = sender.try_send(Message::Close(None));
+        }
+        if client.clean_session {
+            self.remove_all_subscriptions(client_id);
+            self.clients.remove(client_id);
+        } else {
+            client.connected_status = ConnectionStatus::Disconnected;
+        }
+        true
+    }
+
+    // A clean session ends with the connection, otherwise the session is kept for the client to resume.
+    // The will is discarded either way, a client that disconnects cleanly must not trigger it [MQTT-3.14.4-3]
+    pub fn disconnect_client(&mut self, client_id: &str) -> bool {
+        let Some(client) = self.clients.get_mut(client_id) else {
+            return false;
+        };
+        client.will = None;
+        if client.clean_session {
+            self.remove_all_subscriptions(client_id);
+            self.clients.remove(client_id);
+        } else {
+            client.connected_status = ConnectionStatus::Disconnected;
+            client.sender = None;
+            client.priority_sender = None;
+        }
+        true
+    }
+
+    pub fn record_received(&mut self, bytes: usize) {
+        self.messages_received += 1;
+        self.bytes_received += bytes as u64;
+        self.record_metric(|sink| {
+            sink.record_counter("messages.received", 1);
+            sink.record_counter("bytes.received", bytes as u64);
+        });
+    }
+
+    pub fn record_sent(&mut self, bytes: usize) {
+        self.messages_sent += 1;
+        self.bytes_sent += bytes as u64;
+        self.record_metric(|sink| {
+            sink.record_counter("messages.sent", 1);
+            sink.record_counter("bytes.sent", bytes as u64);
+        });
+    }
+
+    pub fn stats(&self) -> BrokerStats {
+        BrokerStats {
+            connected_clients: self.clients.values().filter(|client| client.is_connected()).count(),
+            total_connected: self.total_connected,
+            total_subscriptions: self.clients.values().map(|client| client.subscriptions.len()).sum(),
+            retained_messages: self.retained.len(),
+            messages_received: self.messages_received,
+            messages_sent: self.messages_sent,
+            bytes_received: self.bytes_received,
+            bytes_sent: self.bytes_sent,
+            excessive_fan_outs: self.excessive_fan_outs,
+            uptime: self.started_at.elapsed(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod packet_id_tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate_starts_at_one() {
+        let mut allocator = PacketIdAllocator::new();
+        assert_eq!(allocator.allocate(), Some(1));
+        assert_eq!(allocator.allocate(), Some(2));
+    }
+
+    #[test]
+    fn test_allocate_until_exhausted_never_returns_zero() {
+        let mut allocator = PacketIdAllocator::new();
+        for expected in 1..=u16::MAX {
+            assert_eq!(allocator.allocate(), Some(expected));
+        }
+        assert_eq!(allocator.in_use(), u16::MAX as usize);
+        assert_eq!(allocator.allocate(), None);
+    }
+
+    #[test]
+    fn test_wraparound_reuses_only_freed_ids() {
+        let mut allocator = PacketIdAllocator::new();
+        for _ in 1..=u16::MAX {
+            allocator.allocate();
+        }
+        assert!(allocator.release(7));
+        assert!(allocator.release(3));
+        // the counter wraps past 65535 to 1 and skips ids still in flight
+        assert_eq!(allocator.allocate(), Some(3));
+        assert_eq!(allocator.allocate(), Some(7));
+        assert_eq!(allocator.allocate(), None);
+    }
+
+    #[test]
+    fn test_release_unknown_id() {
+        let mut allocator = PacketIdAllocator::new();
+        assert!(!allocator.release(42));
+    }
+}
+
+#[cfg(test)]
+mod broker_tests {
+    use super::*;
+
+    #[test]
+    fn test_disconnect_client() {
+        let mut broker = Broker::new();
+        broker.add_client("clean", 60);
+        broker.add_client("persistent", 60);
+        broker.clients.get_mut("persistent").unwrap().clean_session = false;
+
+        assert!(broker.disconnect_client("clean"));
+        assert!(broker.disconnect_client("persistent"));
+        assert!(!broker.disconnect_client("unknown"));
+        assert!(broker.get_client("clean").is_none());
+        assert!(!broker.is_client_connected("persistent"));
+        assert_eq!(broker.stats().connected_clients, 0);
+    }
+
+    #[test]
+    fn test_take_over_client_closes_old_connection() {
+        let mut broker = Broker::new();
+        let (sender, mut receiver) = mpsc::channel(1);
+        broker.add_client("c1", 60);
+        broker.set_client_sender("c1", sender);
+
+        assert!(broker.take_over_client("c1"));
+        assert_eq!(receiver.try_recv().unwrap(), Message::Close(None));
+        assert!(broker.get_client("c1").is_none());
+        assert!(!broker.take_over_client("c1"));
+    }
+
+    #[test]
+    fn test_take_over_keeps_persistent_session() {
+        let mut broker = Broker::new();
+        broker.connect_client("c1", 60, false);
+        broker.add_subscription("c1", "a/b");
+        assert!(broker.take_over_client("c1"));
+        assert!(!broker.is_client_connected("c1"));
+        assert!(broker.connect_client("c1", 60, false));
+        assert!(broker.get_client("c1").unwrap().subscriptions.contains("a/b"));
+    }
+
+    #[test]
+    fn test_connect_client_session_present() {
+        let mut broker = Broker::new();
+        assert!(!broker.connect_client("clean", 60, true));
+        assert!(!broker.connect_client("persistent", 60, false));
+        broker.add_subscription("persistent", "a/b");
+        broker.disconnect_client("clean");
+        broker.disconnect_client("persistent");
+
+        assert!(!broker.connect_client("clean", 60, true));
+        assert!(broker.connect_client("persistent", 30, false));
+        let client = broker.get_client("persistent").unwrap();
+        assert!(client.is_connected());
+        assert_eq!(client.keep_alive, Duration::from_secs(30));
+        assert!(client.subscriptions.contains("a/b"));
+
+        // a clean session discards the stored one
+        broker.disconnect_client("persistent");
+        assert!(!broker.connect_client("persistent", 60, true));
+        assert!(broker.get_client("persistent").unwrap().subscriptions.is_empty());
+    }
+
+    #[test]
+    fn test_generate_client_id_uses_prefix() {
+        let config = BrokerConfig {
+            generated_client_id_prefix: "gen_".to_string(),
+            generated_client_id_length: 6,
+            generated_client_id_charset: "xyz".to_string(),
+            ..BrokerConfig::default()
+        };
+        let broker = Broker::with_config(config);
+        let client_id = broker.generate_client_id().unwrap();
+        assert!(client_id.starts_with("gen_"));
+        assert_eq!(client_id.len(), 10);
+        assert!(client_id["gen_".len()..].chars().all(|c| "xyz".contains(c)));
+    }
+
+    #[test]
+    fn test_generate_client_id_is_unique() {
+        let config = BrokerConfig {
+            generated_client_id_prefix: "gen_".to_string(),
+            generated_client_id_length: 2,
+            generated_client_id_charset: "ab".to_string(),
+            ..BrokerConfig::default()
+        };
+        let mut broker = Broker::with_config(config);
+        // only four ids are possible, each one generated must be free
+        for _ in 0..4 {
+            let client_id = broker.generate_client_id().unwrap();
+            assert!(!broker.is_client_connected(&client_id));
+            broker.add_client(&client_id, 60);
+        }
+        assert_eq!(broker.stats().connected_clients, 4);
+    }
+
+    #[test]
+    fn test_generate_client_id_gives_up_when_exhausted() {
+        let config = BrokerConfig {
+            generated_client_id_prefix: "gen_".to_string(),
+            generated_client_id_length: 1,
+            generated_client_id_charset: "a".to_string(),
+            ..BrokerConfig::default()
+        };
+        let mut broker = Broker::with_config(config);
+        broker.add_client("gen_a", 60);
+        assert_eq!(broker.generate_client_id(), None);
+    }
+
+    #[test]
+    fn test_retain_store_overwrite_and_delete() {
+        let mut broker = Broker::new();
+        broker.retain("a/b", b"one");
+        assert_eq!(broker.retained_message("a/b"), Some(&b"one"[..]));
+        broker.retain("a/b", b"two");
+        assert_eq!(broker.retained_message("a/b"), Some(&b"two"[..]));
+        assert_eq!(broker.stats().retained_messages, 1);
+        broker.retain("a/b", b"");
+        assert_eq!(broker.retained_message("a/b"), None);
+        assert_eq!(broker.stats().retained_messages, 0);
+    }
+
+    #[test]
+    fn test_retained_matching() {
+        let mut broker = Broker::new();
+        broker.retain("a/b", b"1");
+        broker.retain("a/c", b"2");
+        broker.retain("b", b"3");
+        let mut matching = broker.retained_matching("a/+");
+        matching.sort();
+        assert_eq!(matching, vec![("a/b".to_string(), b"1".to_vec()), ("a/c".to_string(), b"2".to_vec())]);
+        assert!(broker.retained_matching("c").is_empty());
+    }
+
+    fn will(topic: &str, message: &str, retain: bool) -> Will {
+        Will {
+            topic: topic.to_string(),
+            message: message.to_string(),
+            qos: 0,
+            retain,
+        }
+    }
+
+    #[test]
+    fn test_connection_lost_publishes_will_once() {
+        let mut broker = Broker::new();
+        let (sender, mut receiver) = mpsc::channel(4);
+        broker.add_client("sub", 60);
+        broker.set_client_sender("sub", sender);
+        broker.add_subscription("sub", "will/#");
+        broker.add_client("c1", 60);
+        broker.clients.get_mut("c1").unwrap().will = Some(will("will/c1", "bye", false));
+
+        assert!(broker.connection_lost("c1"));
+        assert_eq!(receiver.try_recv().unwrap(), Message::Binary(Publish::for_delivery("will/c1", b"bye").to_bytes()));
+        assert!(broker.get_client("c1").is_none());
+        assert!(!broker.connection_lost("c1"));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_connection_lost_retains_will() {
+        let mut broker = Broker::new();
+        broker.add_client("c1", 60);
+        broker.clients.get_mut("c1").unwrap().will = Some(will("will/c1", "bye", true));
+        broker.connection_lost("c1");
+        assert_eq!(broker.retained_message("will/c1"), Some(&b"bye"[..]));
+    }
+
+    #[test]
+    fn test_priority_topics_use_the_priority_queue() {
+        let mut broker = Broker::with_config(BrokerConfig { priority_topics: vec!["ctrl/#".to_string()], ..BrokerConfig::default() });
+        let (sender, mut receiver) = mpsc::channel(4);
+        let (priority_sender, mut priority_receiver) = mpsc::channel(4);
+        broker.add_client("sub", 60);
+        broker.set_client_sender("sub", sender);
+        broker.set_client_priority_sender("sub", priority_sender);
+        broker.add_subscription("sub", "#");
+
+        assert_eq!(broker.route("bulk/data", b"bulk"), 1);
+        assert_eq!(broker.route("ctrl/stop", b"ctrl"), 1);
+        assert_eq!(receiver.try_recv().unwrap(), Message::Binary(b"bulk".to_vec()));
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(priority_receiver.try_recv().unwrap(), Message::Binary(b"ctrl".to_vec()));
+    }
+
+    fn fan_out_broker(config: BrokerConfig, subscribers: usize) -> (Broker, Vec<mpsc::Receiver<Message>>) {
+        let mut broker = Broker::with_config(config);
+        let receivers = (0..subscribers)
+            .map(|i| {
+                let client_id = format!("sub-{}", i);
+                let (sender, receiver) = mpsc::channel(1);
+                broker.add_client(&client_id, 60);
+                broker.set_client_sender(&client_id, sender);
+                broker.add_subscription(&client_id, "#");
+                receiver
+            })
+            .collect();
+        (broker, receivers)
+    }
+
+    #[test]
+    fn test_excessive_fan_out_is_logged() {
+        let config = BrokerConfig { max_fan_out: Some(100), ..BrokerConfig::default() };
+        let (mut broker, mut receivers) = fan_out_broker(config, 1000);
+        assert_eq!(broker.route("a/b", b"hi"), 1000);
+        assert!(receivers.iter_mut().all(|receiver| receiver.try_recv().is_ok()));
+        assert_eq!(broker.stats().excessive_fan_outs, 1);
+        // staying within the limit is not counted
+        broker.config.max_fan_out = Some(1000);
+        broker.route("a/b", b"hi");
+        assert_eq!(broker.stats().excessive_fan_outs, 1);
+    }
+
+    #[test]
+    fn test_excessive_fan_out_is_truncated() {
+        let config = BrokerConfig { max_fan_out: Some(100), fan_out_policy: FanOutPolicy::Truncate, ..BrokerConfig::default() };
+        let (mut broker, mut receivers) = fan_out_broker(config, 1000);
+        assert_eq!(broker.route("a/b", b"hi"), 100);
+        assert_eq!(receivers.iter_mut().map(|receiver| receiver.try_recv()).filter(Result::is_ok).count(), 100);
+        assert_eq!(broker.stats().excessive_fan_outs, 1);
+    }
+
+    #[test]
+    fn test_queue_depth_of_paused_subscriber() {
+        let mut broker = Broker::with_config(BrokerConfig { priority_topics: vec!["ctrl/#".to_string()], ..BrokerConfig::default() });
+        let (sender, mut receiver) = mpsc::channel(8);
+        let (priority_sender, _priority_receiver) = mpsc::channel(8);
+        broker.add_client("sub", 60);
+        assert_eq!(broker.queue_depth("sub"), Some(0));
+        broker.set_client_sender("sub", sender);
+        broker.set_client_priority_sender("sub", priority_sender);
+        broker.add_subscription("sub", "#");
+
+        for depth in 1..=3 {
+            broker.route("bulk/data", b"bulk");
+            assert_eq!(broker.queue_depth("sub"), Some(depth));
+        }
+        broker.route("ctrl/stop", b"ctrl");
+        assert_eq!(broker.queue_depth("sub"), Some(4));
+        receiver.try_recv().unwrap();
+        assert_eq!(broker.queue_depth("sub"), Some(3));
+        assert_eq!(broker.queue_depth("unknown"), None);
+    }
+
+    #[test]
+    fn test_has_subscribers() {
+        let mut broker = Broker::new();
+        assert!(!broker.has_subscribers("a/b"));
+        broker.add_client("sub", 60);
+        broker.add_subscription("sub", "a/+");
+        assert!(broker.has_subscribers("a/b"));
+        assert!(!broker.has_subscribers("x/y"));
+        assert!(!broker.has_subscribers("a/b/c"));
+    }
+
+    #[test]
+    fn test_remove_all_subscriptions() {
+        let mut broker = Broker::new();
+        let (sender, mut receiver) = mpsc::channel(8);
+        broker.add_client("sub", 60);
+        broker.set_client_sender("sub", sender);
+        for index in 0..50 {
+            broker.add_subscription("sub", &format!("topic/{}", index));
+        }
+        broker.add_subscription("sub", "#");
+
+        assert_eq!(broker.remove_all_subscriptions("sub"), 51);
+        assert!(broker.get_client("sub").unwrap().subscriptions.is_empty());
+        assert_eq!(broker.stats().total_subscriptions, 0);
+        assert_eq!(broker.route("topic/7", b"hi"), 0);
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(broker.remove_all_subscriptions("unknown"), 0);
+    }
+
+    #[test]
+    fn test_paused_client_gets_its_messages_on_resume() {
+        let mut broker = Broker::new();
+        let (sender, mut receiver) = mpsc::channel(8);
+        broker.add_client("sub", 60);
+        broker.set_client_sender("sub", sender);
+        broker.add_subscription("sub", "a/#");
+
+        assert!(broker.pause_client("sub"));
+        for payload in [&b"one"[..], b"two", b"three"] {
+            assert_eq!(broker.route("a/b", payload), 1);
+        }
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(broker.queue_depth("sub"), Some(3));
+
+        assert!(broker.resume_client("sub"));
+        for payload in [&b"one"[..], b"two", b"three"] {
+            assert_eq!(receiver.try_recv().unwrap(), Message::Binary(payload.to_vec()));
+        }
+        broker.route("a/b", b"four");
+        assert_eq!(receiver.try_recv().unwrap(), Message::Binary(b"four".to_vec()));
+        assert!(!broker.pause_client("unknown"));
+    }
+
+    #[test]
+    fn test_paused_client_keeps_messages_up_to_its_queue_size() {
+        let mut broker = Broker::new();
+        let (sender, mut receiver) = mpsc::channel(2);
+        broker.add_client("sub", 60);
+        broker.set_client_sender("sub", sender);
+        broker.add_subscription("sub", "a");
+
+        broker.pause_client("sub");
+        assert_eq!(broker.route("a", b"one"), 1);
+        assert_eq!(broker.route("a", b"two"), 1);
+        assert_eq!(broker.route("a", b"three"), 0);
+        broker.resume_client("sub");
+        assert_eq!(receiver.try_recv().unwrap(), Message::Binary(b"one".to_vec()));
+        assert_eq!(receiver.try_recv().unwrap(), Message::Binary(b"two".to_vec()));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_priority_topics_without_priority_queue() {
+        let mut broker = Broker::with_config(BrokerConfig { priority_topics: vec!["ctrl/#".to_string()], ..BrokerConfig::default() });
+        let (sender, mut receiver) = mpsc::channel(4);
+        broker.add_client("sub", 60);
+        broker.set_client_sender("sub", sender);
+        broker.add_subscription("sub", "#");
+        assert_eq!(broker.route("ctrl/stop", b"ctrl"), 1);
+        assert_eq!(receiver.try_recv().unwrap(), Message::Binary(b"ctrl".to_vec()));
+    }
+
+    #[test]
+    fn test_reap_expired_clients() {
+        let mut broker = Broker::with_config(BrokerConfig { keep_alive_grace_factor: 1.5, ..BrokerConfig::default() });
+        let (sender, mut receiver) = mpsc::channel(1);
+        broker.add_client("expired", 10);
+        broker.set_client_sender("expired", sender);
+        broker.clients.get_mut("expired").unwrap().last_seen = SystemTime::now() - Duration::from_secs(16);
+        broker.add_client("within_grace", 10);
+        broker.clients.get_mut("within_grace").unwrap().last_seen = SystemTime::now() - Duration::from_secs(14);
+        broker.add_client("no_keep_alive", 0);
+        broker.clients.get_mut("no_keep_alive").unwrap().last_seen = SystemTime::now() - Duration::from_secs(3600);
+
+        assert_eq!(broker.reap_expired_clients(), vec!["expired".to_string()]);
+        assert_eq!(receiver.try_recv().unwrap(), Message::Close(None));
+        assert!(broker.get_client("expired").is_none());
+        assert!(broker.is_client_connected("within_grace"));
+        assert!(broker.is_client_connected("no_keep_alive"));
+    }
+
+    #[test]
+    fn test_reap_publishes_will() {
+        let mut broker = Broker::new();
+        broker.add_client("c1", 1);
+        broker.clients.get_mut("c1").unwrap().will = Some(will("will/c1", "bye", true));
+        broker.clients.get_mut("c1").unwrap().last_seen = SystemTime::now() - Duration::from_secs(2);
+        broker.reap_expired_clients();
+        assert_eq!(broker.retained_message("will/c1"), Some(&b"bye"[..]));
+    }
+
+    #[test]
+    fn test_stats_empty_broker() {
+        let stats = Broker::new().stats();
+        assert_eq!(stats.connected_clients, 0);
+        assert_eq!(stats.total_connected, 0);
+        assert_eq!(stats.total_subscriptions, 0);
+        assert_eq!(stats.messages_received, 0);
+        assert_eq!(stats.bytes_sent, 0);
+    }
+
+    #[test]
+    fn test_stats_after_activity() {
+        let mut broker = Broker::new();
+        broker.add_client("a", 60);
+        broker.add_client("b", 60);
+        broker.remove_client("a");
+        broker.add_client("c", 60);
+        broker.clients.get_mut("b").unwrap().subscriptions.insert("x/y".to_string());
+        broker.clients.get_mut("c").unwrap().subscriptions.insert("x/#".to_string());
+        broker.record_received(18);
+        broker.record_received(2);
+        broker.record_sent(4);
+
+        let stats = broker.stats();
+        assert_eq!(stats.connected_clients, 2);
+        assert_eq!(stats.total_connected, 3);
+        assert!(stats.connected_clients as u64 <= stats.total_connected);
+        assert_eq!(stats.total_subscriptions, 2);
+        assert_eq!(stats.messages_received, 2);
+        assert_eq!(stats.bytes_received, 20);
+        assert_eq!(stats.messages_sent, 1);
+        assert_eq!(stats.bytes_sent, 4);
+        assert!(stats.bytes_received >= stats.messages_received);
     }
 }
@@ -0,0 +1,318 @@
+use std::sync::Arc;
+
+use log::{error, info, warn};
+use tokio::sync::{mpsc, oneshot};
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use crate::models::broker::Broker;
+use crate::models::config::BrokerConfig;
+use crate::models::decode::IncomingPacket;
+use crate::models::mqtt_types::{ConnAckReturnCode, MqttPacketDispatcher, MqttPacketType};
+use crate::models::packets::connack::ConnAck;
+
+// Commands waiting for the broker task beyond this make the senders wait instead of growing the queue
+const COMMAND_QUEUE_SIZE: usize = 1024;
+const BROKER_STOPPED: &str = "Broker task has stopped";
+
+// The outbound queues of one connection, registered with the broker once its CONNECT is accepted
+#[derive(Debug, Clone)]
+pub struct ConnectionQueues {
+    pub sender: mpsc::Sender<Message>,
+    pub priority_sender: mpsc::Sender<Message>,
+}
+
+// What the broker answered to a packet. `client_id` is set when the packet was a CONNECT
+// that the broker accepted, from then on the connection acts for that client.
+// `close` is set when the connection has to be closed once the response was sent,
+// as after a CONNACK refusing the client [MQTT-3.2.2-5].
+#[derive(Debug)]
+pub struct PacketResponse {
+    pub result: Result<Vec<u8>, &'static str>,
+    pub client_id: Option<String>,
+    pub close: bool,
+}
+
+// Everything the rest of the server asks of the broker. Each command is handled in turn by the
+// broker task, which owns the `Broker`, and replies through its oneshot sender.
+pub enum BrokerCommand {
+    Packet {
+        packet: IncomingPacket,
+        size: usize,
+        client_id: Option<String>,
+        queues: ConnectionQueues,
+        respond: oneshot::Sender<PacketResponse>,
+    },
+    // The connection ended without a DISCONNECT
+    ConnectionClosed {
+        client_id: String,
+        queues: ConnectionQueues,
+    },
+    ReapExpiredClients {
+        respond: oneshot::Sender<Vec<String>>,
+    },
+    Config {
+        respond: oneshot::Sender<BrokerConfig>,
+    },
+    // Runs the closure against the broker, for callers that need more than the commands above
+    Call(Box<dyn FnOnce(&mut Broker) + Send>),
+}
+
+// Cheap to clone handle for sending commands to the broker task
+#[derive(Debug, Clone)]
+pub struct BrokerHandle {
+    commands: mpsc::Sender<BrokerCommand>,
+}
+
+// Moves the broker into its own task. The task stops once every handle is dropped.
+pub fn spawn_broker(broker: Broker, dispatcher: MqttPacketDispatcher) -> BrokerHandle {
+    let (commands, receiver) = mpsc::channel(COMMAND_QUEUE_SIZE);
+    tokio::spawn(run_broker(broker, Arc::new(dispatcher), receiver));
+    BrokerHandle { commands }
+}
+
+async fn run_broker(mut broker: Broker, dispatcher: Arc<MqttPacketDispatcher>, mut commands: mpsc::Receiver<BrokerCommand>) {
+    while let Some(command) = commands.recv().await {
+        match command {
+            BrokerCommand::Packet { mut packet, size, client_id, queues, respond } => {
+                let response = handle_packet(&mut broker, &dispatcher, &mut packet, size, client_id.as_deref(), &queues);
+                // the connection may be gone already, there is nobody left to tell
+                let _ = respond.send(response);
+            }
+            BrokerCommand::ConnectionClosed { client_id, queues } => connection_closed(&mut broker, &client_id, &queues),
+            BrokerCommand::ReapExpiredClients { respond } => {
+                let _ = respond.send(broker.reap_expired_clients());
+            }
+            BrokerCommand::Config { respond } => {
+                let _ = respond.send(broker.config().clone());
+            }
+            BrokerCommand::Call(call) => call(&mut broker),
+        }
+    }
+    info!("Broker task stopped, all handles are gone");
+}
+
+fn handle_packet(
+    broker: &mut Broker,
+    dispatcher: &MqttPacketDispatcher,
+    packet: &mut IncomingPacket,
+    size: usize,
+    client_id: Option<&str>,
+    queues: &ConnectionQueues,
+) -> PacketResponse {
+    let Some(handler) = dispatcher.handlers.get(&packet.packet_type()) else {
+        error!("Unsupported packet type {:?}", packet.packet_type());
+        return PacketResponse { result: Err("Unsupported packet type"), client_id: None, close: true };
+    };
+    broker.record_received(size);
+    // Until its CONNECT is accepted a connection may send nothing else [MQTT-3.1.0-1]
+    if client_id.is_none() && packet.packet_type() != MqttPacketType::Connect {
+        error!("{:?} received before CONNECT", packet.packet_type());
+        return PacketResponse { result: Err("Packet received before CONNECT"), client_id: None, close: true };
+    }
+    // A clean session may connect with an empty client id and gets one assigned [MQTT-3.1.3-6].
+    // When no id is free the id stays empty and the CONNECT is refused with 0x02.
+    if let IncomingPacket::Connect(request) = packet {
+        if request.client_id.is_empty() && request.clean_session {
+            match broker.generate_client_id() {
+                Some(client_id) => {
+                    info!("Assigned client id [{}]", client_id);
+                    request.client_id = client_id;
+                }
+                None => warn!("No free client id left to assign"),
+            }
+        }
+    }
+    let result = handler(packet, client_id, broker);
+    // Only packets that were accepted count as activity, so a client sending
+    // nothing but invalid packets cannot keep itself alive
+    if let (Ok(_), Some(id)) = (&result, client_id) {
+        broker.update_client_activity(id);
+    }
+    // A rejected CONNECT must not touch the session of a client already connected under the same id
+    let mut accepted_client_id = None;
+    let mut close = result.is_err();
+    if let (IncomingPacket::Connect(request), Ok(connack)) = (&*packet, &result) {
+        if !connect_accepted(connack) {
+            close = true;
+        } else if broker.set_client_sender(&request.client_id, queues.sender.clone()) {
+            broker.set_client_priority_sender(&request.client_id, queues.priority_sender.clone());
+            accepted_client_id = Some(request.client_id.clone());
+        }
+    }
+    if let Ok(packet_data) = &result {
+        if !packet_data.is_empty() {
+            broker.record_sent(packet_data.len());
+        }
+    }
+    PacketResponse { result, client_id: accepted_client_id, close }
+}
+
+// The CONNECT handler answers with a CONNACK, return code 0 meaning the client was accepted
+fn connect_accepted(connack: &[u8]) -> bool {
+    ConnAck::from_bytes(connack.to_vec()).is_ok_and(|connack| connack.variable_header.return_code == ConnAckReturnCode::Accepted.to_u8())
+}
+
+// Unless the client was taken over by a new connection, the closed connection still owns
+// the session and its will has to be published
+fn connection_closed(broker: &mut Broker, client_id: &str, queues: &ConnectionQueues) {
+    let owns_session = broker
+        .get_client(client_id)
+        .and_then(|client| client.sender.as_ref())
+        .is_some_and(|session_sender| session_sender.same_channel(&queues.sender));
+    if owns_session {
+        warn!("Connection of [{}] lost without DISCONNECT", client_id);
+        broker.connection_lost(client_id);
+    }
+}
+
+impl BrokerHandle {
+    async fn request<R>(&self, command: impl FnOnce(oneshot::Sender<R>) -> BrokerCommand) -> Result<R, &'static str> {
+        let (respond, response) = oneshot::channel();
+        self.commands.send(command(respond)).await.map_err(|_| BROKER_STOPPED)?;
+        response.await.map_err(|_| BROKER_STOPPED)
+    }
+
+    pub async fn handle_packet(&self, packet: IncomingPacket, size: usize, client_id: Option<String>, queues: ConnectionQueues) -> Result<PacketResponse, &'static str> {
+        self.request(|respond| BrokerCommand::Packet { packet, size, client_id, queues, respond }).await
+    }
+
+    pub async fn connection_closed(&self, client_id: String, queues: ConnectionQueues) -> Result<(), &'static str> {
+        self.commands.send(BrokerCommand::ConnectionClosed { client_id, queues }).await.map_err(|_| BROKER_STOPPED)
+    }
+
+    pub async fn reap_expired_clients(&self) -> Result<Vec<String>, &'static str> {
+        self.request(|respond| BrokerCommand::ReapExpiredClients { respond }).await
+    }
+
+    pub async fn config(&self) -> Result<BrokerConfig, &'static str> {
+        self.request(|respond| BrokerCommand::Config { respond }).await
+    }
+
+    pub async fn call<R, F>(&self, call: F) -> Result<R, &'static str>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Broker) -> R + Send + 'static,
+    {
+        self.request(|respond| {
+            BrokerCommand::Call(Box::new(move |broker: &mut Broker| {
+                let _ = respond.send(call(broker));
+            }))
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod broker_actor_tests {
+    use super::*;
+    use crate::models::decode::decode;
+    use crate::models::packets::connect::Will;
+
+    fn queues() -> (ConnectionQueues, mpsc::Receiver<Message>) {
+        let (sender, receiver) = mpsc::channel(8);
+        let (priority_sender, _) = mpsc::channel(8);
+        (ConnectionQueues { sender, priority_sender }, receiver)
+    }
+
+    // CONNECT with clean session, keep alive of 60 seconds and the client id "c1"
+    const CONNECT: [u8; 16] = [0x10, 0x0E, 0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, 0x04, 0x02, 0x00, 0x3C, 0x00, 0x02, 0x63, 0x31];
+
+    #[tokio::test]
+    async fn test_connect_registers_the_connection() {
+        let broker = spawn_broker(Broker::new(), MqttPacketDispatcher::new().unwrap());
+        let (queues, _receiver) = queues();
+        let response = broker.handle_packet(decode(&CONNECT).unwrap(), CONNECT.len(), None, queues).await.unwrap();
+        assert_eq!(response.result, Ok(vec![0x20, 0x02, 0x00, 0x00]));
+        assert_eq!(response.client_id, Some("c1".to_string()));
+        assert!(!response.close);
+        assert!(broker.call(|broker| broker.is_client_connected("c1")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_packets_before_connect_are_rejected() {
+        let broker = spawn_broker(Broker::new(), MqttPacketDispatcher::new().unwrap());
+        let (queues, _receiver) = queues();
+        // retained PUBLISH to "a" with payload "hi"
+        let publish = [0x31, 0x05, 0x00, 0x01, 0x61, 0x68, 0x69];
+        let response = broker.handle_packet(decode(&publish).unwrap(), publish.len(), None, queues.clone()).await.unwrap();
+        assert_eq!(response.result, Err("Packet received before CONNECT"));
+        assert!(!broker.call(|broker| broker.retained_message("a").is_some()).await.unwrap());
+        let response = broker.handle_packet(IncomingPacket::Unsubscribe { packet_id: 1 }, 2, None, queues).await.unwrap();
+        assert!(response.result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_packets_are_all_answered() {
+        let broker = spawn_broker(Broker::new(), MqttPacketDispatcher::new().unwrap());
+        let (queues, _receiver) = queues();
+        broker.handle_packet(decode(&CONNECT).unwrap(), CONNECT.len(), None, queues.clone()).await.unwrap();
+        let pings: Vec<_> = (0..100)
+            .map(|_| {
+                let broker = broker.clone();
+                let queues = queues.clone();
+                tokio::spawn(async move { broker.handle_packet(IncomingPacket::PingReq, 2, Some("c1".to_string()), queues).await })
+            })
+            .collect();
+        for ping in pings {
+            assert_eq!(ping.await.unwrap().unwrap().result, Ok(vec![0xD0, 0x00]));
+        }
+        assert_eq!(broker.call(|broker| broker.stats().messages_received).await.unwrap(), 101);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_connect_leaves_live_session_alone() {
+        let broker = spawn_broker(Broker::new(), MqttPacketDispatcher::new().unwrap());
+        let (victim_queues, _victim_receiver) = queues();
+        let (attacker_queues, _attacker_receiver) = queues();
+        broker.handle_packet(decode(&CONNECT).unwrap(), CONNECT.len(), None, victim_queues).await.unwrap();
+        broker.call(|broker| broker.get_client_mut("c1").unwrap().will = Some(Will { topic: "will/c1".to_string(), message: "bye".to_string(), qos: 0, retain: true })).await.unwrap();
+
+        // the same CONNECT announcing protocol level 3, which is refused
+        let mut rejected = CONNECT;
+        rejected[8] = 0x03;
+        let response = broker.handle_packet(decode(&rejected).unwrap(), rejected.len(), None, attacker_queues.clone()).await.unwrap();
+        assert_eq!(response.result, Ok(vec![0x20, 0x02, 0x00, 0x01]));
+        assert_eq!(response.client_id, None);
+        assert!(response.close);
+
+        broker.connection_closed("c1".to_string(), attacker_queues).await.unwrap();
+        assert!(broker.call(|broker| broker.is_client_connected("c1")).await.unwrap());
+        assert_eq!(broker.call(|broker| broker.retained_message("will/c1").map(<[u8]>::to_vec)).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_connect_is_refused_when_no_client_id_is_free() {
+        let config = BrokerConfig {
+            generated_client_id_prefix: "gen_".to_string(),
+            generated_client_id_length: 1,
+            generated_client_id_charset: "a".to_string(),
+            ..BrokerConfig::default()
+        };
+        let broker = spawn_broker(Broker::with_config(config), MqttPacketDispatcher::new().unwrap());
+        // CONNECT with clean session, keep alive of 60 seconds and an empty client id
+        let connect = [0x10, 0x0C, 0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, 0x04, 0x02, 0x00, 0x3C, 0x00, 0x00];
+        let (first_queues, _first_receiver) = queues();
+        let response = broker.handle_packet(decode(&connect).unwrap(), connect.len(), None, first_queues).await.unwrap();
+        assert_eq!(response.client_id, Some("gen_a".to_string()));
+
+        // the only possible id is taken now
+        let (second_queues, _second_receiver) = queues();
+        let response = broker.handle_packet(decode(&connect).unwrap(), connect.len(), None, second_queues).await.unwrap();
+        assert_eq!(response.result, Ok(vec![0x20, 0x02, 0x00, 0x02]));
+        assert_eq!(response.client_id, None);
+        assert!(response.close);
+    }
+
+    #[tokio::test]
+    async fn test_connection_closed_only_for_session_owner() {
+        let broker = spawn_broker(Broker::new(), MqttPacketDispatcher::new().unwrap());
+        let (old_queues, _old_receiver) = queues();
+        let (new_queues, _new_receiver) = queues();
+        broker.handle_packet(decode(&CONNECT).unwrap(), CONNECT.len(), None, old_queues.clone()).await.unwrap();
+        broker.handle_packet(decode(&CONNECT).unwrap(), CONNECT.len(), None, new_queues).await.unwrap();
+
+        // the taken over connection closing must not end the new connection's session
+        broker.connection_closed("c1".to_string(), old_queues).await.unwrap();
+        assert!(broker.call(|broker| broker.is_client_connected("c1")).await.unwrap());
+    }
+}
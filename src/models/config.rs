@@ -0,0 +1,74 @@
+use std::time::Duration;
+
+// Tunable limits of the broker. The defaults are generous, deployments can tighten them as needed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BrokerConfig {
+    pub max_client_id_length: usize,
+    pub max_username_length: usize,
+    pub max_password_length: usize,
+    pub max_will_topic_length: usize,
+    pub max_will_message_length: usize,
+    // Client ids assigned to clients connecting with an empty one are the prefix followed by
+    // `generated_client_id_length` characters drawn from the charset
+    pub generated_client_id_prefix: String,
+    pub generated_client_id_length: usize,
+    pub generated_client_id_charset: String,
+    // How often clients are checked for an expired keep alive
+    pub keep_alive_check_interval: Duration,
+    // A client is dropped once it was silent for this many times its keep alive [MQTT-3.1.2-24]
+    pub keep_alive_grace_factor: f64,
+    // Connections that have not completed their CONNECT yet, further sockets are closed right after accepting them
+    pub max_pending_connects: usize,
+    // Connections whose CONNECT was not accepted within this long after accepting the socket are closed,
+    // including WebSocket handshakes that never complete
+    pub connect_timeout: Duration,
+    // Largest packet accepted from a client, the header included. Checked against the fixed header
+    // before the body is read, so an announced length alone cannot make the broker allocate.
+    pub max_packet_size: usize,
+    // Messages on topics matching one of these filters skip ahead of the other messages queued for a subscriber.
+    // Empty by default, so every subscriber gets its messages in the order they were published.
+    pub priority_topics: Vec<String>,
+    // Serve raw MQTT over TCP and WebSocket on the same port, telling them apart by the first byte
+    // a client sends. Off by default, the port then only accepts WebSocket upgrades.
+    pub detect_transport: bool,
+    // Port serving raw MQTT over TCP, next to the WebSocket port
+    pub tcp_port: u16,
+    // Publishes matching more subscribers than this are logged and counted in `BrokerStats::excessive_fan_outs`,
+    // the policy decides whether they still reach every subscriber. No limit by default.
+    pub max_fan_out: Option<usize>,
+    pub fan_out_policy: FanOutPolicy,
+}
+
+// What happens to a publish matching more subscribers than `max_fan_out`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FanOutPolicy {
+    // Deliver to every subscriber anyway
+    Log,
+    // Deliver to `max_fan_out` of the subscribers, in no particular order, and drop it for the rest
+    Truncate,
+}
+
+impl Default for BrokerConfig {
+    fn default() -> Self {
+        BrokerConfig {
+            max_client_id_length: 256,
+            max_username_length: 256,
+            max_password_length: 256,
+            max_will_topic_length: 1024,
+            max_will_message_length: u16::MAX as usize,
+            generated_client_id_prefix: "auto-".to_string(),
+            generated_client_id_length: 16,
+            generated_client_id_charset: "0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ".to_string(),
+            keep_alive_check_interval: Duration::from_secs(1),
+            keep_alive_grace_factor: 1.5,
+            max_pending_connects: 1024,
+            connect_timeout: Duration::from_secs(10),
+            max_packet_size: 1024 * 1024,
+            priority_topics: Vec::new(),
+            detect_transport: false,
+            tcp_port: 1884,
+            max_fan_out: None,
+            fan_out_policy: FanOutPolicy::Log,
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use crate::models::error::MqttError;
+use crate::models::mqtt_headers::{parse_packet_id, MqttHeaders};
+use crate::models::mqtt_types::MqttPacketType;
+use crate::models::packets::{connect::ConnectRequest, publish::Publish, pubrel::PubRel, subscribe::Subscribe};
+
+// A packet received from a client, decoded from the wire so the dispatcher handlers
+// only deal with typed values and the broker
+#[derive(Debug)]
+pub enum IncomingPacket {
+    Connect(ConnectRequest),
+    ConnAck,
+    Publish(Publish),
+    PubAck { packet_id: u16 },
+    PubRec { packet_id: u16 },
+    PubRel { packet_id: u16 },
+    PubComp { packet_id: u16 },
+    Subscribe(Subscribe),
+    SubAck,
+    Unsubscribe { packet_id: u16 },
+    UnsubAck,
+    PingReq,
+    PingResp,
+    Disconnect,
+}
+
+impl IncomingPacket {
+    pub fn packet_type(&self) -> MqttPacketType {
+        match self {
+            IncomingPacket::Connect(_) => MqttPacketType::Connect,
+            IncomingPacket::ConnAck => MqttPacketType::ConnAck,
+            IncomingPacket::Publish(_) => MqttPacketType::Publish,
+            IncomingPacket::PubAck { .. } => MqttPacketType::PubAck,
+            IncomingPacket::PubRec { .. } => MqttPacketType::PubRec,
+            IncomingPacket::PubRel { .. } => MqttPacketType::PubRel,
+            IncomingPacket::PubComp { .. } => MqttPacketType::PubComp,
+            IncomingPacket::Subscribe(_) => MqttPacketType::Subscribe,
+            IncomingPacket::SubAck => MqttPacketType::SubAck,
+            IncomingPacket::Unsubscribe { .. } => MqttPacketType::Unsubscribe,
+            IncomingPacket::UnsubAck => MqttPacketType::UnsubAck,
+            IncomingPacket::PingReq => MqttPacketType::PingReq,
+            IncomingPacket::PingResp => MqttPacketType::PingResp,
+            IncomingPacket::Disconnect => MqttPacketType::Disconnect,
+        }
+    }
+}
+
+pub fn decode(data: &[u8]) -> Result<IncomingPacket, MqttError> {
+    let fixed_header = MqttHeaders::parse(data)?;
+    let variable_header = &data[fixed_header.incomming_byte_size()..];
+    let packet = match fixed_header.packet_type {
+        MqttPacketType::Connect => IncomingPacket::Connect(ConnectRequest::from_bytes(data.to_vec())?),
+        MqttPacketType::ConnAck => IncomingPacket::ConnAck,
+        MqttPacketType::Publish => IncomingPacket::Publish(Publish::from_bytes(data.to_vec())?),
+        MqttPacketType::PubAck => IncomingPacket::PubAck { packet_id: parse_packet_id(variable_header)? },
+        MqttPacketType::PubRec => IncomingPacket::PubRec { packet_id: parse_packet_id(variable_header)? },
+        MqttPacketType::PubRel => IncomingPacket::PubRel { packet_id: PubRel::from_bytes(data.to_vec())?.variable_header.packet_id },
+        MqttPacketType::PubComp => IncomingPacket::PubComp { packet_id: parse_packet_id(variable_header)? },
+        MqttPacketType::Subscribe => IncomingPacket::Subscribe(Subscribe::from_bytes(data.to_vec())?),
+        MqttPacketType::SubAck => IncomingPacket::SubAck,
+        MqttPacketType::Unsubscribe => IncomingPacket::Unsubscribe { packet_id: parse_packet_id(variable_header)? },
+        MqttPacketType::UnsubAck => IncomingPacket::UnsubAck,
+        MqttPacketType::PingReq => IncomingPacket::PingReq,
+        MqttPacketType::PingResp => IncomingPacket::PingResp,
+        MqttPacketType::Disconnect => IncomingPacket::Disconnect,
+    };
+    Ok(packet)
+}
+
+#[cfg(test)]
+mod decode_tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_header_only_packets() {
+        assert!(matches!(decode(&[0xC0, 0x00]), Ok(IncomingPacket::PingReq)));
+        assert!(matches!(decode(&[0xE0, 0x00]), Ok(IncomingPacket::Disconnect)));
+        assert!(matches!(decode(&[0xD0, 0x00]), Ok(IncomingPacket::PingResp)));
+    }
+
+    #[test]
+    fn test_decode_publish() {
+        let packet = decode(&[0x30, 0x05, 0x00, 0x01, 0x61, 0x68, 0x69]).unwrap();
+        assert_eq!(packet.packet_type(), MqttPacketType::Publish);
+        match packet {
+            IncomingPacket::Publish(publish) => assert_eq!(publish.variable_header.topic_name, "a"),
+            other => panic!("Expected Publish, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decode_packet_ids() {
+        assert!(matches!(decode(&[0x40, 0x02, 0x00, 0x07]), Ok(IncomingPacket::PubAck { packet_id: 7 })));
+        assert!(matches!(decode(&[0xA2, 0x05, 0x00, 0x02, 0x00, 0x01, 0x61]), Ok(IncomingPacket::Unsubscribe { packet_id: 2 })));
+        assert!(decode(&[0x40, 0x02, 0x00, 0x00]).is_err());
+        assert!(decode(&[0x82, 0x00]).is_err());
+    }
+
+    #[test]
+    fn test_decode_rejects_wrong_fixed_header_flags() {
+        assert!(matches!(decode(&[0x62, 0x02, 0x00, 0x07]), Ok(IncomingPacket::PubRel { packet_id: 7 })));
+        assert!(matches!(decode(&[0x60, 0x02, 0x00, 0x07]), Err(MqttError::MalformedPacket(_))));
+        assert!(matches!(decode(&[0x80, 0x06, 0x00, 0x01, 0x00, 0x01, 0x61, 0x00]), Err(MqttError::MalformedPacket(_))));
+    }
+
+    #[test]
+    fn test_decode_malformed_connect() {
+        assert!(decode(&[0x10, 0x00]).is_err());
+    }
+
+    #[test]
+    fn test_decode_truncated_packets() {
+        let packets: [&[u8]; 6] = [
+            &[0x10, 0x0F, 0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, 0x04, 0x02, 0x00, 0x3C, 0x00, 0x03, 0x61, 0x62, 0x63],
+            &[0x32, 0x07, 0x00, 0x01, 0x61, 0x00, 0x0A, 0x68, 0x69],
+            &[0x82, 0x06, 0x00, 0x01, 0x00, 0x01, 0x61, 0x00],
+            &[0x40, 0x02, 0x00, 0x07],
+            &[0x62, 0x02, 0x00, 0x07],
+            &[0xC0, 0x00],
+        ];
+        for packet in packets {
+            assert!(decode(packet).is_ok());
+            for length in 0..packet.len() {
+                assert!(decode(&packet[..length]).is_err(), "{:02X?} cut at {} was accepted", packet, length);
+            }
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use std::fmt;
+
+use crate::models::mqtt_types::MqttPacketType;
+
+// Why a packet could not be decoded. Parsers check every length before touching the buffer,
+// so malformed input ends up here instead of panicking the connection task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MqttError {
+    // The buffer ends before the named field
+    Truncated(&'static str),
+    // The named string field is not well formed UTF-8 [MQTT-1.5.3-1]
+    InvalidUtf8(&'static str),
+    // The fixed header announces a different packet than the parser handles
+    UnexpectedPacketType(MqttPacketType),
+    // Any other violation of the protocol, the text names the rule
+    MalformedPacket(&'static str),
+    // The fixed header announces a packet of this many bytes, more than the broker accepts
+    PacketTooLarge(usize),
+}
+
+impl fmt::Display for MqttError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MqttError::Truncated(field) => write!(f, "Buffer is too short to contain the {}", field),
+            MqttError::InvalidUtf8(field) => write!(f, "{} is not valid UTF-8", field),
+            MqttError::UnexpectedPacketType(packet_type) => write!(f, "Unexpected packet type {:?}", packet_type),
+            MqttError::MalformedPacket(reason) => write!(f, "{}", reason),
+            MqttError::PacketTooLarge(size) => write!(f, "Packet of {} bytes exceeds the maximum packet size", size),
+        }
+    }
+}
+
+impl std::error::Error for MqttError {}
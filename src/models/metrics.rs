@@ -0,0 +1,272 @@
+use std::fmt;
+#[cfg(any(feature = "statsd", feature = "otlp"))]
+use std::io;
+#[cfg(any(feature = "statsd", feature = "otlp"))]
+use std::net::ToSocketAddrs;
+#[cfg(feature = "statsd")]
+use std::net::UdpSocket;
+#[cfg(feature = "otlp")]
+use std::{io::{Read, Write}, net::{SocketAddr, TcpStream}, sync::{mpsc, Mutex}, thread, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+#[cfg(any(feature = "statsd", feature = "otlp"))]
+use log::warn;
+
+// Receives the broker counters as they change, for environments that push metrics instead of polling `Broker::stats`.
+// Recording must not block, the broker task calls it between commands.
+pub trait MetricSink: Send + Sync + fmt::Debug {
+    fn record_counter(&self, name: &str, value: u64);
+    fn record_gauge(&self, name: &str, value: u64);
+    fn record_histogram(&self, name: &str, value: f64);
+}
+
+// Sends every metric as one StatsD line in its own UDP datagram, e.g. `mqtt.messages.published:1|c`.
+// UDP never waits for the receiver, a lost datagram only loses that sample.
+#[cfg(feature = "statsd")]
+#[derive(Debug)]
+pub struct StatsdSink {
+    socket: UdpSocket,
+    prefix: String,
+}
+
+#[cfg(feature = "statsd")]
+impl StatsdSink {
+    pub fn new<A: ToSocketAddrs>(target: A, prefix: &str) -> io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_nonblocking(true)?;
+        socket.connect(target)?;
+        Ok(StatsdSink {
+            socket,
+            prefix: prefix.to_string(),
+        })
+    }
+
+    fn send(&self, name: &str, value: &str, metric_type: &str) {
+        let line = if self.prefix.is_empty() {
+            format!("{}:{}|{}", name, value, metric_type)
+        } else {
+            format!("{}.{}:{}|{}", self.prefix, name, value, metric_type)
+        };
+        if let Err(e) = self.socket.send(line.as_bytes()) {
+            warn!("Failed to send StatsD metric [{}]: {}", line, e);
+        }
+    }
+}
+
+#[cfg(feature = "statsd")]
+impl MetricSink for StatsdSink {
+    fn record_counter(&self, name: &str, value: u64) {
+        self.send(name, &value.to_string(), "c");
+    }
+
+    fn record_gauge(&self, name: &str, value: u64) {
+        self.send(name, &value.to_string(), "g");
+    }
+
+    fn record_histogram(&self, name: &str, value: f64) {
+        self.send(name, &value.to_string(), "h");
+    }
+}
+
+#[cfg(feature = "otlp")]
+const OTLP_METRICS_PATH: &str = "/v1/metrics";
+#[cfg(feature = "otlp")]
+const OTLP_TIMEOUT: Duration = Duration::from_secs(5);
+// Counters are sent as the increase since the previous export
+#[cfg(feature = "otlp")]
+const AGGREGATION_TEMPORALITY_DELTA: u8 = 1;
+
+#[cfg(feature = "otlp")]
+#[derive(Debug)]
+enum Sample {
+    Counter(String, u64),
+    Gauge(String, u64),
+    Histogram(String, f64),
+}
+
+// Exports metrics to an OpenTelemetry collector as OTLP/HTTP requests with a JSON body, posted to `/v1/metrics`.
+// Recording only queues the sample, a background thread posts whatever queued up in one request,
+// so a slow or unreachable collector never holds up the broker.
+#[cfg(feature = "otlp")]
+#[derive(Debug)]
+pub struct OtlpSink {
+    samples: Mutex<mpsc::Sender<Sample>>,
+}
+
+#[cfg(feature = "otlp")]
+impl OtlpSink {
+    pub fn new<A: ToSocketAddrs>(collector: A, service_name: &str) -> io::Result<Self> {
+        let collector = collector
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No address for the OTLP collector"))?;
+        let (sender, receiver) = mpsc::channel();
+        let service_name = service_name.to_string();
+        thread::Builder::new()
+            .name("otlp-exporter".to_string())
+            .spawn(move || Self::export(collector, &service_name, receiver))?;
+        Ok(OtlpSink { samples: Mutex::new(sender) })
+    }
+
+    fn record(&self, sample: Sample) {
+        let samples = self.samples.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if samples.send(sample).is_err() {
+            warn!("OTLP exporter has stopped, dropping metric");
+        }
+    }
+
+    // Runs until the sink is dropped
+    fn export(collector: SocketAddr, service_name: &str, receiver: mpsc::Receiver<Sample>) {
+        while let Ok(sample) = receiver.recv() {
+            let mut samples = vec![sample];
+            samples.extend(receiver.try_iter());
+            let body = Self::request_body(service_name, &samples);
+            if let Err(e) = Self::post(collector, &body) {
+                warn!("Failed to export {} metrics to {}: {}", samples.len(), collector, e);
+            }
+        }
+    }
+
+    fn post(collector: SocketAddr, body: &str) -> io::Result<()> {
+        let mut stream = TcpStream::connect_timeout(&collector, OTLP_TIMEOUT)?;
+        stream.set_read_timeout(Some(OTLP_TIMEOUT))?;
+        stream.set_write_timeout(Some(OTLP_TIMEOUT))?;
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            OTLP_METRICS_PATH,
+            collector,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes())?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        let status = response.lines().next().unwrap_or_default();
+        if status.split(' ').nth(1).is_some_and(|code| code.starts_with('2')) {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!("collector answered [{}]", status)))
+        }
+    }
+
+    // An ExportMetricsServiceRequest in the OTLP JSON encoding, 64 bit integers are written as strings
+    fn request_body(service_name: &str, samples: &[Sample]) -> String {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        let metrics: Vec<String> = samples
+            .iter()
+            .map(|sample| match sample {
+                Sample::Counter(name, value) => format!(
+                    r#"{{"name":"{}","sum":{{"dataPoints":[{{"asInt":"{}","timeUnixNano":"{}"}}],"aggregationTemporality":{},"isMonotonic":true}}}}"#,
+                    json_escape(name), value, time, AGGREGATION_TEMPORALITY_DELTA
+                ),
+                Sample::Gauge(name, value) => format!(
+                    r#"{{"name":"{}","gauge":{{"dataPoints":[{{"asInt":"{}","timeUnixNano":"{}"}}]}}}}"#,
+                    json_escape(name), value, time
+                ),
+                Sample::Histogram(name, value) => format!(
+                    r#"{{"name":"{}","histogram":{{"dataPoints":[{{"count":"1","sum":{},"bucketCounts":["1"],"explicitBounds":[],"timeUnixNano":"{}"}}],"aggregationTemporality":{}}}}}"#,
+                    json_escape(name), value, time, AGGREGATION_TEMPORALITY_DELTA
+                ),
+            })
+            .collect();
+        format!(
+            r#"{{"resourceMetrics":[{{"resource":{{"attributes":[{{"key":"service.name","value":{{"stringValue":"{}"}}}}]}},"scopeMetrics":[{{"scope":{{"name":"mqtt-broker"}},"metrics":[{}]}}]}}]}}"#,
+            json_escape(service_name),
+            metrics.join(",")
+        )
+    }
+}
+
+#[cfg(feature = "otlp")]
+impl MetricSink for OtlpSink {
+    fn record_counter(&self, name: &str, value: u64) {
+        self.record(Sample::Counter(name.to_string(), value));
+    }
+
+    fn record_gauge(&self, name: &str, value: u64) {
+        self.record(Sample::Gauge(name.to_string(), value));
+    }
+
+    fn record_histogram(&self, name: &str, value: f64) {
+        self.record(Sample::Histogram(name.to_string(), value));
+    }
+}
+
+#[cfg(feature = "otlp")]
+fn json_escape(value: &str) -> String {
+    value.chars().fold(String::new(), |mut escaped, c| {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+        escaped
+    })
+}
+
+#[cfg(all(test, any(feature = "statsd", feature = "otlp")))]
+mod metrics_tests {
+    use super::*;
+    #[cfg(feature = "otlp")]
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    #[cfg(feature = "statsd")]
+    #[test]
+    fn test_statsd_lines() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let sink = StatsdSink::new(receiver.local_addr().unwrap(), "mqtt").unwrap();
+        sink.record_counter("messages.published", 1);
+        sink.record_gauge("clients.connected", 3);
+        sink.record_histogram("messages.delivered", 2.5);
+
+        let mut buffer = [0; 128];
+        let mut lines = Vec::new();
+        for _ in 0..3 {
+            let length = receiver.recv(&mut buffer).unwrap();
+            lines.push(String::from_utf8(buffer[..length].to_vec()).unwrap());
+        }
+        assert_eq!(lines, vec!["mqtt.messages.published:1|c", "mqtt.clients.connected:3|g", "mqtt.messages.delivered:2.5|h"]);
+    }
+
+    #[cfg(feature = "otlp")]
+    #[test]
+    fn test_otlp_request() {
+        let collector = TcpListener::bind("127.0.0.1:0").unwrap();
+        let sink = OtlpSink::new(collector.local_addr().unwrap(), "broker").unwrap();
+        sink.record_counter("messages.published", 1);
+
+        let (mut stream, _) = collector.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let mut request = Vec::new();
+        let mut buffer = [0; 1024];
+        while !String::from_utf8_lossy(&request).ends_with("}]}]}]}") {
+            let length = stream.read(&mut buffer).unwrap();
+            assert!(length > 0, "request ended early");
+            request.extend_from_slice(&buffer[..length]);
+        }
+        stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+
+        let request = String::from_utf8(request).unwrap();
+        assert!(request.starts_with("POST /v1/metrics HTTP/1.1\r\n"));
+        assert!(request.contains("Content-Type: application/json\r\n"));
+        assert!(request.contains(r#""stringValue":"broker""#));
+        assert!(request.contains(r#"{"name":"messages.published","sum":{"dataPoints":[{"asInt":"1","#));
+    }
+
+    #[cfg(feature = "otlp")]
+    #[test]
+    fn test_otlp_body_encodes_every_kind() {
+        let samples = [
+            Sample::Counter("c".to_string(), 2),
+            Sample::Gauge("g".to_string(), 3),
+            Sample::Histogram("h\"".to_string(), 1.5),
+        ];
+        let body = OtlpSink::request_body("broker", &samples);
+        assert!(body.contains(r#""name":"c","sum":{"dataPoints":[{"asInt":"2""#));
+        assert!(body.contains(r#""aggregationTemporality":1,"isMonotonic":true"#));
+        assert!(body.contains(r#""name":"g","gauge":{"dataPoints":[{"asInt":"3""#));
+        assert!(body.contains(r#""name":"h\"","histogram":{"dataPoints":[{"count":"1","sum":1.5,"#));
+    }
+}
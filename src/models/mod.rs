@@ -1,5 +1,15 @@
 pub mod mqtt_types;
 pub mod mqtt_headers;
 pub mod mqtt_payloads;
+pub mod decode;
 pub mod packets;
+pub mod packet_buffer;
+pub mod auth;
 pub mod broker;
+pub mod broker_actor;
+pub mod config;
+pub mod error;
+pub mod metrics;
+pub mod topic;
+#[cfg(test)]
+pub(crate) mod test_packets;
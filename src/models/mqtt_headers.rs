@@ -1,7 +1,8 @@
 use std::any::Any;
 use std::mem;
-use log::{info, warn, error};
+use log::info;
 
+use crate::models::error::MqttError;
 use crate::models::mqtt_types::MqttPacketType;
 
 
@@ -14,20 +15,32 @@ pub struct MqttHeaders {
 }
 
 impl MqttHeaders {
+    const MAX_REMAINING_LENGTH_BYTES: usize = 4;
 
     pub fn new(packet_type: MqttPacketType, flags: u8, remaining_length: u32) -> Self {
         MqttHeaders {
             packet_type,
             flags,
             remaining_length,
-            remaining_length_bytes: 1, // TODO: calculate the length here again
+            remaining_length_bytes: Self::encoded_length_size(remaining_length),
         }
     }
+
+    // Number of bytes `to_bytes` uses to encode the remaining length, seven bits per byte
+    fn encoded_length_size(remaining_length: u32) -> usize {
+        let mut size = 1;
+        let mut remaining_length = remaining_length / 128;
+        while remaining_length > 0 {
+            size += 1;
+            remaining_length /= 128;
+        }
+        size
+    }
     // byte1: message type (4 bits) + flags (4 bits)
     // byte2: remaining length (variable length encoding)
-    pub fn parse(buffer: &[u8]) -> Result<Self, &'static str> {
+    pub fn parse(buffer: &[u8]) -> Result<Self, MqttError> {
         if buffer.len() < 2 {
-            return Err("Buffer is too short to contain an MQTT Fixed Header");
+            return Err(MqttError::Truncated("Fixed Header"));
         }
 
         let byte1 = buffer[0];
@@ -47,30 +60,36 @@ impl MqttHeaders {
             12 => MqttPacketType::PingReq,
             13 => MqttPacketType::PingResp,
             14 => MqttPacketType::Disconnect,
-            _ => return Err("Invalid MQTT Packet Type"),
+            _ => return Err(MqttError::MalformedPacket("Invalid MQTT Packet Type")),
         };
 
         let flags = byte1 & 0x0F;
 
+        // The remaining length is a variable byte integer of at most four bytes, each byte carrying
+        // seven bits of the value and a continuation bit [MQTT-2.2.3]
         let mut multiplier = 1;
         let mut value = 0;
-        let mut index = 1;
-        while index < buffer.len() {
-            let encoded_byte = buffer[index];
+        let mut remaining_length_bytes = 0;
+        loop {
+            if remaining_length_bytes == Self::MAX_REMAINING_LENGTH_BYTES {
+                return Err(MqttError::MalformedPacket("Remaining Length is longer than four bytes"));
+            }
+            let Some(&encoded_byte) = buffer.get(1 + remaining_length_bytes) else {
+                return Err(MqttError::Truncated("Remaining Length"));
+            };
+            remaining_length_bytes += 1;
             value += (encoded_byte & 127) as u32 * multiplier;
             multiplier *= 128;
             if encoded_byte & 128 == 0 {
                 break;
             }
-            index += 1;
         }
 
-
         Ok(MqttHeaders {
             packet_type,
             flags,
             remaining_length: value,
-            remaining_length_bytes: index,
+            remaining_length_bytes,
         })
     }
 
@@ -101,8 +120,14 @@ impl MqttHeaders {
         mem::size_of::<u8>() + mem::size_of::<u32>()
     }
 
+    // The packet type byte followed by the encoded remaining length
     pub fn incomming_byte_size(&self) -> usize {
-        self.remaining_length_bytes + 1
+        1 + self.remaining_length_bytes
+    }
+
+    // Size of the whole packet this header announces, the header included
+    pub fn packet_size(&self) -> usize {
+        self.incomming_byte_size() + self.remaining_length as usize
     }
 }
 
@@ -131,6 +156,17 @@ pub struct SubscribeHeader {
     pub packet_id: u16,
 }
 
+// The variable header of PUBACK, PUBREC, PUBREL and PUBCOMP is just the packet identifier
+#[derive(Debug, Clone, PartialEq)]
+pub struct PacketIdHeader {
+    pub packet_id: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubAckHeader {
+    pub packet_id: u16,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct ConnAckHeader {
     pub session_present: bool,
@@ -167,6 +203,15 @@ impl VariableHeader for SubscribeHeader {
     }
 }
 
+impl VariableHeader for SubAckHeader {
+    fn header_type(&self) -> MqttPacketType {
+        MqttPacketType::SubAck
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
 
 impl VariableHeader for ConnAckHeader {
     fn header_type(&self) -> MqttPacketType {
@@ -179,7 +224,14 @@ impl VariableHeader for ConnAckHeader {
 }
 
 impl ConnectHeader {
+    const PROTOCOL_NAME_LENGTH_PREFIX: usize = 2;
     const PROTOCOL_NAME_LENGTH: usize = 4;
+    const CLEAN_SESSION_FLAG: u8 = 0b00000010;
+    const WILL_FLAG: u8 = 0b00000100;
+    const WILL_QOS_MASK: u8 = 0b00011000;
+    const WILL_RETAIN_FLAG: u8 = 0b00100000;
+    const PASSWORD_FLAG: u8 = 0b01000000;
+    const USER_NAME_FLAG: u8 = 0b10000000;
 
     // Helper function to increment the index and return the previous/old value
     fn increment_index(idx: &mut usize, value: usize) -> usize {
@@ -199,13 +251,18 @@ impl ConnectHeader {
             keep_alive,
         }) 
     }
-    pub fn from_bytes(data: &[u8]) -> Self {
+    pub fn from_bytes(data: &[u8]) -> Result<Self, MqttError> {
         let mut idx: usize = 0;
         // the date variable is expected to not hold the fixed header
+        if data.len() < Self::size() {
+            return Err(MqttError::Truncated("CONNECT Variable Header"));
+        }
 
+        // the protocol name is a length prefixed UTF-8 string (0x00 0x04 "MQTT")
+        Self::increment_index(&mut idx, Self::PROTOCOL_NAME_LENGTH_PREFIX);
         let protocol_name = {
             let start = Self::increment_index(&mut idx, Self::PROTOCOL_NAME_LENGTH);
-            String::from_utf8(data[start..start + Self::PROTOCOL_NAME_LENGTH].to_vec()).unwrap()
+            String::from_utf8(data[start..start + Self::PROTOCOL_NAME_LENGTH].to_vec()).map_err(|_| MqttError::InvalidUtf8("Protocol Name"))?
         };
 
         let protocol_level = {
@@ -227,11 +284,136 @@ impl ConnectHeader {
         info!("Protocol Name: {}", protocol_name);
         info!("Protocol Level: {}", protocol_level);
         info!("Connect Flags: {}", connect_flags);
-        ConnectHeader::new(protocol_name, protocol_level, connect_flags, keep_alive).unwrap()
+        ConnectHeader::new(protocol_name, protocol_level, connect_flags, keep_alive).map_err(|_| MqttError::MalformedPacket("Invalid Protocol Name"))
+    }
+
+    pub fn size() -> usize {
+        Self::PROTOCOL_NAME_LENGTH_PREFIX + Self::PROTOCOL_NAME_LENGTH + mem::size_of::<u8>() + mem::size_of::<u8>() + mem::size_of::<u16>()
+    }
+
+    pub fn clean_session(&self) -> bool {
+        self.connect_flags & Self::CLEAN_SESSION_FLAG != 0
+    }
+
+    pub fn will_flag(&self) -> bool {
+        self.connect_flags & Self::WILL_FLAG != 0
+    }
+
+    pub fn will_qos(&self) -> u8 {
+        (self.connect_flags & Self::WILL_QOS_MASK) >> 3
+    }
+
+    pub fn will_retain(&self) -> bool {
+        self.connect_flags & Self::WILL_RETAIN_FLAG != 0
+    }
+
+    pub fn password_flag(&self) -> bool {
+        self.connect_flags & Self::PASSWORD_FLAG != 0
+    }
+
+    pub fn user_name_flag(&self) -> bool {
+        self.connect_flags & Self::USER_NAME_FLAG != 0
+    }
+}
+
+// SUBSCRIBE, UNSUBSCRIBE and QoS > 0 PUBLISH packets MUST carry a non-zero packet identifier [MQTT-2.3.1-1]
+pub fn parse_packet_id(data: &[u8]) -> Result<u16, MqttError> {
+    if data.len() < 2 {
+        return Err(MqttError::Truncated("Packet Identifier"));
+    }
+    let packet_id = u16::from_be_bytes([data[0], data[1]]);
+    if packet_id == 0 {
+        return Err(MqttError::MalformedPacket("Packet Identifier must be non-zero"));
+    }
+    Ok(packet_id)
+}
+
+impl PublishHeader {
+    // The topic name is always present, the packet identifier only for QoS 1 and 2
+    pub fn from_bytes(data: &[u8], qos: u8) -> Result<Self, MqttError> {
+        if data.len() < 2 {
+            return Err(MqttError::Truncated("Topic Name"));
+        }
+        let topic_length = u16::from_be_bytes([data[0], data[1]]) as usize;
+        if data.len() < 2 + topic_length {
+            return Err(MqttError::Truncated("Topic Name"));
+        }
+        let topic_name = String::from_utf8(data[2..2 + topic_length].to_vec()).map_err(|_| MqttError::InvalidUtf8("Topic Name"))?;
+        let packet_id = if qos > 0 {
+            parse_packet_id(&data[2 + topic_length..])?
+        } else {
+            0
+        };
+        Ok(PublishHeader {
+            topic_name,
+            packet_id,
+        })
+    }
+
+    pub fn to_bytes(&self, qos: u8) -> Vec<u8> {
+        let mut bytes = (self.topic_name.len() as u16).to_be_bytes().to_vec();
+        bytes.extend(self.topic_name.as_bytes());
+        if qos > 0 {
+            bytes.extend(self.packet_id.to_be_bytes());
+        }
+        bytes
+    }
+
+    pub fn size(&self, qos: u8) -> usize {
+        let packet_id_size = if qos > 0 { mem::size_of::<u16>() } else { 0 };
+        mem::size_of::<u16>() + self.topic_name.len() + packet_id_size
+    }
+}
+
+impl SubscribeHeader {
+    pub fn from_bytes(data: &[u8]) -> Result<Self, MqttError> {
+        Ok(SubscribeHeader {
+            packet_id: parse_packet_id(data)?,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.packet_id.to_be_bytes().to_vec()
+    }
+
+    pub fn size() -> usize {
+        mem::size_of::<u16>()
+    }
+}
+
+impl PacketIdHeader {
+    pub fn new(packet_id: u16) -> Self {
+        Self { packet_id }
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, MqttError> {
+        Ok(PacketIdHeader::new(parse_packet_id(data)?))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.packet_id.to_be_bytes().to_vec()
     }
 
     pub fn size() -> usize {
-        Self::PROTOCOL_NAME_LENGTH + mem::size_of::<u8>() + mem::size_of::<u8>() + mem::size_of::<u16>()
+        mem::size_of::<u16>()
+    }
+}
+
+impl SubAckHeader {
+    pub fn new(packet_id: u16) -> Self {
+        Self { packet_id }
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, MqttError> {
+        Ok(SubAckHeader::new(parse_packet_id(data)?))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.packet_id.to_be_bytes().to_vec()
+    }
+
+    pub fn size() -> usize {
+        mem::size_of::<u16>()
     }
 }
 
@@ -246,26 +428,21 @@ impl ConnAckHeader {
         }
     }
 
-    pub fn from_bytes(data: &[u8]) -> Self {
-        let session_present = if data[0] & Self::SESSION_PRESENT_INVALID_MASK == 0 && data[0] & Self::SESSION_PRESENT_MASK == 1 {
-            true
-        } else {
-            false
-        };
+    pub fn from_bytes(data: &[u8]) -> Result<Self, MqttError> {
+        if data.len() < Self::incomming_byte_size() {
+            return Err(MqttError::Truncated("CONNACK Variable Header"));
+        }
+        // Bits 7-1 of the acknowledge flags are reserved and must be 0 (section 3.2.2.1)
+        if data[0] & Self::SESSION_PRESENT_INVALID_MASK != 0 {
+            return Err(MqttError::MalformedPacket("CONNACK reserved flags must be 0"));
+        }
+        let session_present = data[0] & Self::SESSION_PRESENT_MASK == Self::SESSION_PRESENT_MASK;
         let return_code = data[1];
-        ConnAckHeader::new(session_present, return_code)
+        Ok(ConnAckHeader::new(session_present, return_code))
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut buffer = Vec::new();
-        let session_present_as_byte = if self.session_present == true {
-            0b00000001 as u8
-        } else {
-            0b00000000 as u8
-        }; // TODO: cleaner way?
-        buffer.push(session_present_as_byte);
-        buffer.push(self.return_code);
-        buffer
+        vec![self.session_present as u8, self.return_code]
     }
 
     pub fn incomming_byte_size() -> usize {
@@ -286,6 +463,39 @@ mod mqtt_headers_tests {
         assert_eq!(headers.remaining_length, 0);
     }
 
+    #[test]
+    fn test_parse_remaining_length_boundaries() {
+        let cases: [(&[u8], u32, usize); 4] = [
+            (&[0x30, 0x00], 0, 1),
+            (&[0x30, 0x7F], 127, 1),
+            (&[0x30, 0x80, 0x01], 128, 2),
+            (&[0x30, 0xFF, 0x7F], 16383, 2),
+        ];
+        for (buffer, remaining_length, remaining_length_bytes) in cases {
+            let headers = MqttHeaders::parse(buffer).unwrap();
+            assert_eq!(headers.remaining_length, remaining_length);
+            assert_eq!(headers.remaining_length_bytes, remaining_length_bytes);
+            assert_eq!(headers.incomming_byte_size(), 1 + remaining_length_bytes);
+        }
+    }
+
+    #[test]
+    fn test_parse_malformed_remaining_length() {
+        assert!(MqttHeaders::parse(&[0x30, 0x80]).is_err());
+        assert!(MqttHeaders::parse(&[0x30, 0xFF, 0xFF, 0xFF, 0xFF, 0x01]).is_err());
+        assert_eq!(MqttHeaders::parse(&[0x30, 0xFF, 0xFF, 0xFF, 0x7F]).unwrap().remaining_length, 268_435_455);
+    }
+
+    #[test]
+    fn test_new_computes_remaining_length_bytes() {
+        assert_eq!(MqttHeaders::new(MqttPacketType::Publish, 0, 0).remaining_length_bytes, 1);
+        assert_eq!(MqttHeaders::new(MqttPacketType::Publish, 0, 127).remaining_length_bytes, 1);
+        let headers = MqttHeaders::new(MqttPacketType::Publish, 0, 300);
+        assert_eq!(headers.remaining_length_bytes, 2);
+        assert_eq!(headers.to_bytes().len(), 1 + headers.remaining_length_bytes);
+        assert_eq!(MqttHeaders::new(MqttPacketType::Publish, 0, 268_435_455).remaining_length_bytes, 4);
+    }
+
     #[test]
     fn test_to_bytes() {
         let headers = MqttHeaders {
@@ -315,8 +525,8 @@ mod mqtt_headers_tests {
 
     #[test]
     fn test_connect_header_from_bytes() {
-        let data = vec![0x4D, 0x51, 0x54, 0x54, 0x04, 0x00, 0x00, 0x3C];
-        let header = ConnectHeader::from_bytes(&data);
+        let data = vec![0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, 0x04, 0x00, 0x00, 0x3C];
+        let header = ConnectHeader::from_bytes(&data).unwrap();
         assert_eq!(header.protocol_name, "MQTT");
         assert_eq!(header.protocol_level, 4);
         assert_eq!(header.connect_flags, 0);
@@ -325,27 +535,99 @@ mod mqtt_headers_tests {
 
     #[test]
     fn test_connect_header_from_bytes_connect_flags() { 
-        let data = vec![0x4D, 0x51, 0x54, 0x54, 0x04, 0xC4, 0x00, 0x3C];
-        let header = ConnectHeader::from_bytes(&data);
+        let data = vec![0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, 0x04, 0xC4, 0x00, 0x3C];
+        let header = ConnectHeader::from_bytes(&data).unwrap();
         assert_eq!(header.protocol_name, "MQTT");
         assert_eq!(header.protocol_level, 4);
         assert_eq!(header.connect_flags, 0xC4);
         assert_eq!(header.keep_alive, 60);
     }
 
+    #[test]
+    fn test_connect_header_flags() {
+        let header = ConnectHeader::new("MQTT".to_string(), 4, 0b11110110, 60).unwrap();
+        assert!(header.clean_session());
+        assert!(header.will_flag());
+        assert_eq!(header.will_qos(), 2);
+        assert!(header.will_retain());
+        assert!(header.password_flag());
+        assert!(header.user_name_flag());
+
+        let header = ConnectHeader::new("MQTT".to_string(), 4, 0b00000000, 60).unwrap();
+        assert!(!header.clean_session());
+        assert!(!header.will_flag());
+        assert_eq!(header.will_qos(), 0);
+        assert!(!header.will_retain());
+        assert!(!header.password_flag());
+        assert!(!header.user_name_flag());
+    }
+
+    #[test]
+    fn test_publish_header_from_bytes() {
+        let data = vec![0x00, 0x03, 0x61, 0x2F, 0x62, 0x00, 0x0A, 0x68, 0x69];
+        let header = PublishHeader::from_bytes(&data, 1).unwrap();
+        assert_eq!(header.topic_name, "a/b");
+        assert_eq!(header.packet_id, 10);
+        assert_eq!(header.size(1), 7);
+
+        let header = PublishHeader::from_bytes(&data, 0).unwrap();
+        assert_eq!(header.topic_name, "a/b");
+        assert_eq!(header.packet_id, 0);
+        assert_eq!(header.size(0), 5);
+    }
+
+    #[test]
+    fn test_publish_header_zero_packet_id() {
+        let data = vec![0x00, 0x03, 0x61, 0x2F, 0x62, 0x00, 0x00];
+        assert_eq!(PublishHeader::from_bytes(&data, 1), Err(MqttError::MalformedPacket("Packet Identifier must be non-zero")));
+    }
+
+    #[test]
+    fn test_subscribe_header_from_bytes() {
+        assert_eq!(SubscribeHeader::from_bytes(&[0x01, 0x02]), Ok(SubscribeHeader { packet_id: 0x0102 }));
+        assert_eq!(SubscribeHeader::from_bytes(&[0x00, 0x00]), Err(MqttError::MalformedPacket("Packet Identifier must be non-zero")));
+        assert!(SubscribeHeader::from_bytes(&[0x01]).is_err());
+    }
+
     #[test]
     fn test_connack_header_from_bytes_valid() {
         let data = vec![0x01, 0x00];
-        let header = ConnAckHeader::from_bytes(&data);
-        assert_eq!(header.session_present, true);
+        let header = ConnAckHeader::from_bytes(&data).unwrap();
+        assert!(header.session_present);
         assert_eq!(header.return_code, 0);
     }
 
     #[test]
     fn test_connack_header_from_bytes_invalid() {
         let data = vec![0xA1, 0x00];
-        let header = ConnAckHeader::from_bytes(&data);
-        assert_eq!(header.session_present, false);
-        assert_eq!(header.return_code, 0);
+        assert_eq!(ConnAckHeader::from_bytes(&data).err(), Some(MqttError::MalformedPacket("CONNACK reserved flags must be 0")));
+    }
+
+    #[test]
+    fn test_connack_header_round_trip() {
+        for session_present in [false, true] {
+            let header = ConnAckHeader::new(session_present, 0x00);
+            let bytes = header.to_bytes();
+            assert_eq!(bytes, vec![session_present as u8, 0x00]);
+            let parsed = ConnAckHeader::from_bytes(&bytes).unwrap();
+            assert_eq!(parsed.session_present, session_present);
+            assert_eq!(parsed.return_code, 0x00);
+        }
+    }
+
+    #[test]
+    fn test_short_variable_headers_are_rejected() {
+        let connect = [0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, 0x04, 0x00, 0x00, 0x3C];
+        for length in 0..connect.len() {
+            assert_eq!(ConnectHeader::from_bytes(&connect[..length]), Err(MqttError::Truncated("CONNECT Variable Header")));
+        }
+        assert_eq!(ConnectHeader::from_bytes(&[0x00, 0x04, 0xFF, 0x51, 0x54, 0x54, 0x04, 0x00, 0x00, 0x3C]), Err(MqttError::InvalidUtf8("Protocol Name")));
+        assert!(ConnAckHeader::from_bytes(&[0x01]).is_err());
+        assert!(PacketIdHeader::from_bytes(&[0x01]).is_err());
+        assert!(SubAckHeader::from_bytes(&[]).is_err());
+        assert_eq!(PublishHeader::from_bytes(&[0x00], 0), Err(MqttError::Truncated("Topic Name")));
+        assert_eq!(PublishHeader::from_bytes(&[0x00, 0x03, 0x61], 0), Err(MqttError::Truncated("Topic Name")));
+        assert_eq!(PublishHeader::from_bytes(&[0x00, 0x01, 0x61, 0x00], 1), Err(MqttError::Truncated("Packet Identifier")));
+        assert_eq!(PublishHeader::from_bytes(&[0x00, 0x01, 0xFF], 0), Err(MqttError::InvalidUtf8("Topic Name")));
     }
 }
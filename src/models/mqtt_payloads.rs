@@ -1,5 +1,6 @@
-use super::mqtt_headers::{ConnectHeader, PublishHeader, SubscribeHeader, VariableHeader};
-use log::{info, warn, error};
+use super::error::MqttError;
+use super::mqtt_headers::{ConnectHeader, PublishHeader, SubAckHeader, SubscribeHeader, VariableHeader};
+use log::{info, error};
 
 #[derive(Debug)]
 pub struct ConnectPayload {
@@ -17,8 +18,40 @@ pub struct PublishPayload {
 
 #[derive(Debug)]
 pub struct SubscribePayload {
-    pub subscription_topic: String,
-    pub qos: u8,
+    // Topic filters with their requested QoS, in the order they appear in the packet
+    pub topic_filters: Vec<(String, u8)>,
+}
+
+// One return code per topic filter of the SUBSCRIBE, in the same order [MQTT-3.9.3-1]
+#[derive(Debug)]
+pub struct SubAckPayload {
+    pub return_codes: Vec<u8>,
+}
+
+impl PublishPayload {
+    // The application message is sent as is, without a length prefix [MQTT-3.3.3]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.payload.clone()
+    }
+}
+
+impl SubscribePayload {
+    // Each topic filter is length prefixed and followed by its requested QoS byte [MQTT-3.8.3]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for (topic_filter, qos) in &self.topic_filters {
+            bytes.extend((topic_filter.len() as u16).to_be_bytes());
+            bytes.extend(topic_filter.as_bytes());
+            bytes.push(*qos);
+        }
+        bytes
+    }
+}
+
+impl SubAckPayload {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.return_codes.clone()
+    }
 }
 
 #[derive(Debug, Default)]
@@ -29,27 +62,31 @@ pub enum Payload {
     Connect(ConnectPayload),
     Publish(PublishPayload),
     Subscribe(SubscribePayload),
+    SubAck(SubAckPayload),
     Default(Default),
 }
 
 pub struct PayloadFactory;
 
 impl PayloadFactory {
-    const WILL_FLAG: u8 = 0b00000100;
-    const USER_NAME_FLAG: u8 = 0b10000000;
-    const PASSWORD_FLAG: u8 = 0b01000000;
-    const QOS_MASK_VALID: u8 = 0b00000011;
     const QOS_MASK_INVALID: u8 = 0b11111100;
 
-    fn extract_utf8_string(payload_data: &[u8], start_idx: &mut usize) -> (usize, String) {
+    // Reads a length prefixed UTF-8 string, `field` names it in the error
+    fn extract_utf8_string(payload_data: &[u8], start_idx: &mut usize, field: &'static str) -> Result<(usize, String), MqttError> {
+        if payload_data.len() < *start_idx + 2 {
+            return Err(MqttError::Truncated(field));
+        }
         let string_length: usize = (payload_data[*start_idx] as usize) << 8 | payload_data[*start_idx + 1] as usize;
         *start_idx += 2;
-        let extracted_string: String = String::from_utf8(payload_data[*start_idx..string_length + *start_idx].to_vec()).unwrap();
+        if payload_data.len() < *start_idx + string_length {
+            return Err(MqttError::Truncated(field));
+        }
+        let extracted_string: String = String::from_utf8(payload_data[*start_idx..string_length + *start_idx].to_vec()).map_err(|_| MqttError::InvalidUtf8(field))?;
         *start_idx += string_length;
-        (string_length, extracted_string)
+        Ok((string_length, extracted_string))
     }
 
-    pub fn parse_payload(variable_header: &dyn VariableHeader, payload_data: Vec<u8>) -> Payload {
+    pub fn parse_payload(variable_header: &dyn VariableHeader, payload_data: Vec<u8>) -> Result<Payload, MqttError> {
         if let Some(connect_header) = variable_header.as_any().downcast_ref::<ConnectHeader>() {
             // The ClientId MUST be the first field in the CONNECT packet [MQTT-3.1.3-1]
             // The ClientId MUST be present and its value MUST be a non-zero-length UTF-7 encoded string [MQTT-3.1.3-3]
@@ -58,23 +95,21 @@ impl PayloadFactory {
             // "0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ" [MQTT-3.1.3-5]
             
             // take teh first two bytes of the payload data to get the length of the client id
-            let mut payload_idx: usize = 0 as usize;
-            let (client_id_length, client_id) = Self::extract_utf8_string(&payload_data, &mut payload_idx);
+            let mut payload_idx: usize = 0;
+            let (client_id_length, client_id) = Self::extract_utf8_string(&payload_data, &mut payload_idx, "Client Identifier")?;
             info!("Client ID: [{}] with a length of {}", client_id, client_id_length);
 
             if client_id_length == 0 {
-                //TODO: maybe allow for empty client id and generate a random one
-                //TODO: set Client Clean Session to 1 if client id is empty
-                //TODO: If the Client supplies a zero-byte ClientId with CleanSession set to 0, the Server MUST respond to the CONNECT Packet with a CONNACK return code 0x02 (Identifier rejected) and then close the Network Connection [MQTT-3.1.3-8].
-                error!("Client ID cannot be empty");
+                // the connection assigns an id to a clean session, otherwise the CONNECT is rejected
+                info!("Client ID is empty");
             }
             if client_id_length > 23 {
                 error!("Client ID cannot be longer than 23 bytes");
             }
 
-            let (will_topic, will_message) = if connect_header.connect_flags & Self::WILL_FLAG != 0 {
-                let (will_topic_length, will_topic) = Self::extract_utf8_string(&payload_data, &mut payload_idx);
-                let (will_message_length, will_message) = Self::extract_utf8_string(&payload_data, &mut payload_idx);
+            let (will_topic, will_message) = if connect_header.will_flag() {
+                let (will_topic_length, will_topic) = Self::extract_utf8_string(&payload_data, &mut payload_idx, "Will Topic")?;
+                let (will_message_length, will_message) = Self::extract_utf8_string(&payload_data, &mut payload_idx, "Will Message")?;
                 info!("Will Topic: [{}] with a length of {}", will_topic, will_topic_length);
                 info!("Will Message: [{}] with a length of {}", will_message, will_message_length);
                 (will_topic, will_message)
@@ -82,50 +117,70 @@ impl PayloadFactory {
                 (String::new(), String::new())
             };
 
-            let user_name = if connect_header.connect_flags & Self::USER_NAME_FLAG != 0 {
-                let (user_name_length, user_name) = Self::extract_utf8_string(&payload_data, &mut payload_idx);
+            let user_name = if connect_header.user_name_flag() {
+                let (user_name_length, user_name) = Self::extract_utf8_string(&payload_data, &mut payload_idx, "User Name")?;
                 info!("User Name: [{}] with a length of {}", user_name, user_name_length);
                 user_name
             } else {
                 String::new()
             };
 
-            let password = if connect_header.connect_flags & Self::PASSWORD_FLAG != 0 {
-                let (password_length, password) = Self::extract_utf8_string(&payload_data, &mut payload_idx);
+            let password = if connect_header.password_flag() {
+                let (password_length, password) = Self::extract_utf8_string(&payload_data, &mut payload_idx, "Password")?;
                 info!("Password: [{}] with a length of {}", password, password_length);
                 password
             } else {
                 String::new()
             };
             
-            Payload::Connect(ConnectPayload {
+            Ok(Payload::Connect(ConnectPayload {
                 client_id: Some(client_id),
                 will_topic: Some(will_topic),
                 will_message: Some(will_message),
                 username: Some(user_name),
                 password: Some(password),
-            })
+            }))
         } else if let Some(_publish_header) = variable_header.as_any().downcast_ref::<PublishHeader>() {
-            Payload::Publish(PublishPayload {
+            Ok(Payload::Publish(PublishPayload {
                 payload: payload_data,
-            })
+            }))
         } else if let Some(_subscribe_header) = variable_header.as_any().downcast_ref::<SubscribeHeader>() {
-            let mut payload_idx: usize = 0 as usize;
-            let (subscription_topic_length, subscription_topic) = Self::extract_utf8_string(&payload_data, &mut payload_idx);
-            info!("Subscription Topic: [{}] with a length of {}", subscription_topic, subscription_topic_length);
-            let mut qos = payload_data[payload_idx];
-            // validate qos byte format top most 6 bits should be 0
-            if qos & Self::QOS_MASK_INVALID != 0 {
-                error!("Invalid QoS value");
+            let mut payload_idx: usize = 0;
+            let mut topic_filters = Vec::new();
+            while payload_idx < payload_data.len() {
+                // each entry is a length prefixed topic filter followed by one QoS byte
+                let remaining = payload_data.len() - payload_idx;
+                let topic_length = if remaining >= 2 {
+                    (payload_data[payload_idx] as usize) << 8 | payload_data[payload_idx + 1] as usize
+                } else {
+                    usize::MAX
+                };
+                // A partial entry makes the whole packet malformed [MQTT-4.8.0-1]
+                if remaining < 2 || remaining < topic_length + 3 {
+                    error!("Topic filter {} is truncated", topic_filters.len());
+                    return Err(MqttError::Truncated("Topic Filter"));
+                }
+                let (subscription_topic_length, subscription_topic) = Self::extract_utf8_string(&payload_data, &mut payload_idx, "Topic Filter")?;
+                info!("Subscription Topic: [{}] with a length of {}", subscription_topic, subscription_topic_length);
+                let qos = payload_data[payload_idx];
+                payload_idx += 1;
+                // The top most 6 bits of the requested QoS are reserved and must be 0 [MQTT-3.8.3-4]
+                if qos & Self::QOS_MASK_INVALID != 0 {
+                    error!("Invalid QoS value for topic filter {} [{}]", topic_filters.len(), subscription_topic);
+                    return Err(MqttError::MalformedPacket("Reserved bits of the requested QoS are set"));
+                }
+                topic_filters.push((subscription_topic, qos));
             }
-            qos &= Self::QOS_MASK_VALID;
-            Payload::Subscribe(SubscribePayload {
-                subscription_topic,
-                qos,
-            })
+            Ok(Payload::Subscribe(SubscribePayload {
+                topic_filters,
+            }))
+        } else if let Some(_suback_header) = variable_header.as_any().downcast_ref::<SubAckHeader>() {
+            Ok(Payload::SubAck(SubAckPayload {
+                return_codes: payload_data,
+            }))
         }
         else {
-            Payload::Default(Default::default())
+            Ok(Payload::Default(Default))
         }
     }
     
@@ -151,7 +206,7 @@ mod payload_tests {
             0x00, 0x00, // User Name: 
             0x00, 0x00, // Password: 
         ];
-        let payload = PayloadFactory::parse_payload(&connect_header, payload_data);
+        let payload = PayloadFactory::parse_payload(&connect_header, payload_data).unwrap();
         match payload {
             Payload::Connect(connect_payload) => {
                 assert_eq!(connect_payload.client_id.unwrap(), "test");
@@ -179,7 +234,7 @@ mod payload_tests {
             0x00, 0x04, 0x74, 0x65, 0x73, 0x74, // User Name: test
             0x00, 0x04, 0x74, 0x65, 0x73, 0x74, // Password: test
         ];
-        let payload = PayloadFactory::parse_payload(&connect_header, payload_data);
+        let payload = PayloadFactory::parse_payload(&connect_header, payload_data).unwrap();
         match payload {
             Payload::Connect(connect_payload) => {
                 assert_eq!(connect_payload.client_id.unwrap(), "test");
@@ -199,7 +254,7 @@ mod payload_tests {
             packet_id: 0,
         };
         let payload_data: Vec<u8> = vec![0x00, 0x01, 0x02, 0x03];
-        let payload = PayloadFactory::parse_payload(&publish_header, payload_data);
+        let payload = PayloadFactory::parse_payload(&publish_header, payload_data).unwrap();
         match payload {
             Payload::Publish(publish_payload) => {
                 assert_eq!(publish_payload.payload, vec![0x00, 0x01, 0x02, 0x03]);
@@ -217,14 +272,86 @@ mod payload_tests {
             0x00, 0x04, 0x74, 0x65, 0x73, 0x74, // Subscription Topic: test
             0x01, // QoS: 1
         ];
-        let payload = PayloadFactory::parse_payload(&subscribe_header, payload_data);
+        let payload = PayloadFactory::parse_payload(&subscribe_header, payload_data).unwrap();
         match payload {
             Payload::Subscribe(subscribe_payload) => {
-                assert_eq!(subscribe_payload.subscription_topic, "test");
-                assert_eq!(subscribe_payload.qos, 1);
+                assert_eq!(subscribe_payload.topic_filters, vec![("test".to_string(), 1)]);
             },
             _ => error!("Invalid payload type"),
         }
     }
-}
 
+    #[test]
+    fn test_subscribe_payload_two_filters() {
+        let subscribe_header = SubscribeHeader {
+            packet_id: 1,
+        };
+        let payload_data: Vec<u8> = vec![
+            0x00, 0x01, 0x62, 0x02, // b, QoS 2
+            0x00, 0x03, 0x61, 0x2F, 0x62, 0x00, // a/b, QoS 0
+        ];
+        match PayloadFactory::parse_payload(&subscribe_header, payload_data).unwrap() {
+            Payload::Subscribe(subscribe_payload) => {
+                assert_eq!(subscribe_payload.topic_filters, vec![("b".to_string(), 2), ("a/b".to_string(), 0)]);
+            },
+            other => panic!("Expected SubscribePayload, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_subscribe_payload_three_filters() {
+        let subscribe_header = SubscribeHeader {
+            packet_id: 1,
+        };
+        let payload_data: Vec<u8> = vec![
+            0x00, 0x01, 0x7A, 0x01, // z, QoS 1
+            0x00, 0x01, 0x61, 0x00, // a, QoS 0
+            0x00, 0x01, 0x23, 0x02, // #, QoS 2
+        ];
+        match PayloadFactory::parse_payload(&subscribe_header, payload_data).unwrap() {
+            Payload::Subscribe(subscribe_payload) => {
+                let expected = vec![("z".to_string(), 1), ("a".to_string(), 0), ("#".to_string(), 2)];
+                assert_eq!(subscribe_payload.topic_filters, expected);
+            },
+            other => panic!("Expected SubscribePayload, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_subscribe_payload_truncated_entry() {
+        let subscribe_header = SubscribeHeader {
+            packet_id: 1,
+        };
+        // second entry is missing its QoS byte
+        let payload_data: Vec<u8> = vec![0x00, 0x01, 0x61, 0x00, 0x00, 0x01, 0x62];
+        assert_eq!(PayloadFactory::parse_payload(&subscribe_header, payload_data).unwrap_err(), MqttError::Truncated("Topic Filter"));
+    }
+
+    #[test]
+    fn test_subscribe_payload_reserved_qos_bits() {
+        let subscribe_header = SubscribeHeader {
+            packet_id: 1,
+        };
+        // "a" requested with QoS 1 and the top most bit set
+        let payload_data: Vec<u8> = vec![0x00, 0x01, 0x61, 0x81];
+        assert_eq!(
+            PayloadFactory::parse_payload(&subscribe_header, payload_data).unwrap_err(),
+            MqttError::MalformedPacket("Reserved bits of the requested QoS are set")
+        );
+    }
+
+    #[test]
+    fn test_connect_payload_truncated() {
+        let connect_header = ConnectHeader {
+            connect_flags: 0b11000100,
+            keep_alive: 60,
+            protocol_name: "MQTT".to_string(),
+            protocol_level: 4,
+        };
+        assert_eq!(PayloadFactory::parse_payload(&connect_header, vec![]).unwrap_err(), MqttError::Truncated("Client Identifier"));
+        assert_eq!(PayloadFactory::parse_payload(&connect_header, vec![0x00, 0x04, 0x74]).unwrap_err(), MqttError::Truncated("Client Identifier"));
+        // client id "t" followed by a will topic that ends early
+        assert_eq!(PayloadFactory::parse_payload(&connect_header, vec![0x00, 0x01, 0x74, 0x00, 0x04]).unwrap_err(), MqttError::Truncated("Will Topic"));
+        assert_eq!(PayloadFactory::parse_payload(&connect_header, vec![0x00, 0x01, 0xFF]).unwrap_err(), MqttError::InvalidUtf8("Client Identifier"));
+    }
+}
@@ -1,18 +1,13 @@
 use std::collections::HashMap;
-use std::future::Future;
-
-use futures::stream::SplitSink;
-use futures::SinkExt;
-use tokio_tungstenite::tungstenite::Message;
-use tokio_tungstenite::WebSocketStream;
-use tokio::net::TcpStream;
+use std::fmt;
 
 use log::{info, warn, error};
-use crate::models::mqtt_payloads::Default;
-use crate::models::mqtt_headers::{ConnAckHeader, ConnectHeader, MqttHeaders};
-use crate::models::packets::{connect::Connect, connack::ConnAck};
-use crate::models::mqtt_payloads::{Payload, PayloadFactory};
-use crate::models::broker::Broker;
+use crate::models::decode::IncomingPacket;
+use crate::models::mqtt_headers::MqttHeaders;
+use crate::models::packets::{connect::ConnectRequest, connack::ConnAck, puback::PubAck, pubcomp::PubComp, publish::Publish, pubrec::PubRec, suback::SubAck};
+use crate::models::broker::{Broker, HeldMessage};
+use crate::models::config::BrokerConfig;
+use crate::models::topic::{is_valid_filter, is_valid_topic_name};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum MqttPacketType {
@@ -54,6 +49,35 @@ impl MqttPacketType {
     }
 }
 
+// Return codes of the CONNACK variable header [MQTT-3.2.2.3]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnAckReturnCode {
+    Accepted = 0,
+    UnacceptableProtocolVersion = 1,
+    IdentifierRejected = 2,
+    ServerUnavailable = 3,
+    BadCredentials = 4,
+    NotAuthorized = 5,
+}
+
+impl ConnAckReturnCode {
+    pub fn from_u8(value: u8) -> Result<Self, &'static str> {
+        match value {
+            0 => Ok(ConnAckReturnCode::Accepted),
+            1 => Ok(ConnAckReturnCode::UnacceptableProtocolVersion),
+            2 => Ok(ConnAckReturnCode::IdentifierRejected),
+            3 => Ok(ConnAckReturnCode::ServerUnavailable),
+            4 => Ok(ConnAckReturnCode::BadCredentials),
+            5 => Ok(ConnAckReturnCode::NotAuthorized),
+            _ => Err("Invalid CONNACK Return Code"),
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        self as u8
+    }
+}
+
 #[cfg(test)]
 mod packet_type_tests {
     use super::*;
@@ -76,146 +100,830 @@ mod packet_type_tests {
         assert_eq!(MqttPacketType::from_u8(14), Ok(MqttPacketType::Disconnect));
         assert_eq!(MqttPacketType::from_u8(15), Err("Invalid MQTT Packet Type"));
     }
+
+    #[test]
+    fn test_connack_return_code_round_trip() {
+        let codes = [
+            ConnAckReturnCode::Accepted,
+            ConnAckReturnCode::UnacceptableProtocolVersion,
+            ConnAckReturnCode::IdentifierRejected,
+            ConnAckReturnCode::ServerUnavailable,
+            ConnAckReturnCode::BadCredentials,
+            ConnAckReturnCode::NotAuthorized,
+        ];
+        for (value, code) in codes.into_iter().enumerate() {
+            assert_eq!(code.to_u8(), value as u8);
+            assert_eq!(ConnAckReturnCode::from_u8(value as u8), Ok(code));
+        }
+        assert_eq!(ConnAckReturnCode::from_u8(6), Err("Invalid CONNACK Return Code"));
+    }
 }
 
 
-#[derive(Debug, Clone)]
+// A handler gets the decoded packet and the id of the client that sent it (None before its CONNECT is accepted),
+// and returns the bytes to send back to the client (empty for no response),
+// or an error when the packet is a protocol violation and the connection must be closed.
+// Handlers are boxed closures so embedders can register ones that capture their own state.
+pub type PacketHandler = Box<dyn Fn(&IncomingPacket, Option<&str>, &mut Broker) -> Result<Vec<u8>, &'static str> + Send + Sync>;
+
 pub struct MqttPacketDispatcher {
-    pub handlers: HashMap<MqttPacketType, fn(&Vec<u8>, &mut Broker) -> Vec<u8>>,
+    pub handlers: HashMap<MqttPacketType, PacketHandler>,
+}
+
+impl fmt::Debug for MqttPacketDispatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MqttPacketDispatcher")
+            .field("handlers", &self.handlers.keys().collect::<Vec<_>>())
+            .finish()
+    }
 }
 
 impl MqttPacketDispatcher {
+    // Protocol level of MQTT 3.1.1, the only version the broker speaks
+    const SUPPORTED_PROTOCOL_LEVEL: u8 = 4;
+    // Highest QoS granted to a subscription. A message must reach a subscriber at the lower of its published QoS
+    // and the granted QoS [MQTT-3.8.4-6], so granting QoS 1 needs QoS 1 delivery with its own packet ids and
+    // retransmission. Messages are only forwarded at QoS 0 so far, so every subscription is granted QoS 0.
+    const MAX_GRANTED_QOS: u8 = 0;
+
     pub fn new() -> Result<Self, &'static str> {
-        let mut handlers: HashMap<MqttPacketType, fn(&Vec<u8>, &mut Broker) -> Vec<u8>> = HashMap::new();
-        handlers.insert(MqttPacketType::Connect, MqttPacketDispatcher::handle_connect);
-        handlers.insert(MqttPacketType::ConnAck, MqttPacketDispatcher::handle_connack);
-        handlers.insert(MqttPacketType::Publish, MqttPacketDispatcher::handle_publish);
-        handlers.insert(MqttPacketType::PubAck, MqttPacketDispatcher::handle_puback);
-        handlers.insert(MqttPacketType::PubRec, MqttPacketDispatcher::handle_pubrec);
-        handlers.insert(MqttPacketType::PubRel, MqttPacketDispatcher::handle_pubrel);
-        handlers.insert(MqttPacketType::PubComp, MqttPacketDispatcher::handle_pubcomp);
-        handlers.insert(MqttPacketType::Subscribe, MqttPacketDispatcher::handle_subscribe);
-        handlers.insert(MqttPacketType::SubAck, MqttPacketDispatcher::handle_suback);
-        handlers.insert(MqttPacketType::Unsubscribe, MqttPacketDispatcher::handle_unsubscribe);
-        handlers.insert(MqttPacketType::UnsubAck, MqttPacketDispatcher::handle_unsuback);
-        handlers.insert(MqttPacketType::PingReq, MqttPacketDispatcher::handle_ping_req);
-        handlers.insert(MqttPacketType::PingResp, MqttPacketDispatcher::handle_ping_resp);
-        handlers.insert(MqttPacketType::Disconnect, MqttPacketDispatcher::handle_disconnect);
+        let mut handlers: HashMap<MqttPacketType, PacketHandler> = HashMap::new();
+        handlers.insert(MqttPacketType::Connect, Box::new(MqttPacketDispatcher::handle_connect));
+        handlers.insert(MqttPacketType::ConnAck, Box::new(MqttPacketDispatcher::handle_connack));
+        handlers.insert(MqttPacketType::Publish, Box::new(MqttPacketDispatcher::handle_publish));
+        handlers.insert(MqttPacketType::PubAck, Box::new(MqttPacketDispatcher::handle_puback));
+        handlers.insert(MqttPacketType::PubRec, Box::new(MqttPacketDispatcher::handle_pubrec));
+        handlers.insert(MqttPacketType::PubRel, Box::new(MqttPacketDispatcher::handle_pubrel));
+        handlers.insert(MqttPacketType::PubComp, Box::new(MqttPacketDispatcher::handle_pubcomp));
+        handlers.insert(MqttPacketType::Subscribe, Box::new(MqttPacketDispatcher::handle_subscribe));
+        handlers.insert(MqttPacketType::SubAck, Box::new(MqttPacketDispatcher::handle_suback));
+        handlers.insert(MqttPacketType::Unsubscribe, Box::new(MqttPacketDispatcher::handle_unsubscribe));
+        handlers.insert(MqttPacketType::UnsubAck, Box::new(MqttPacketDispatcher::handle_unsuback));
+        handlers.insert(MqttPacketType::PingReq, Box::new(MqttPacketDispatcher::handle_ping_req));
+        handlers.insert(MqttPacketType::PingResp, Box::new(MqttPacketDispatcher::handle_ping_resp));
+        handlers.insert(MqttPacketType::Disconnect, Box::new(MqttPacketDispatcher::handle_disconnect));
 
         Ok(MqttPacketDispatcher { handlers })
     }
 
+    // Replaces the handler for a packet type, returning the previous one.
+    // To wrap the default behaviour instead of replacing it, take the old handler out of `handlers` first
+    // and call it from the new one.
+    pub fn register(&mut self, packet_type: MqttPacketType, handler: PacketHandler) -> Option<PacketHandler> {
+        self.handlers.insert(packet_type, handler)
+    }
+
 
         // Empty handler functions for each packet type
-    fn handle_connect(data: &Vec<u8>, broker: &mut Broker) -> Vec<u8> {
-        let connect = Connect::from_bytes(data.clone());
-        let connect_payload = match connect.payload as Payload {
-            Payload::Connect(connect_payload) => connect_payload,
-            _ => {
-                error!("Invalid payload type");
-                return Vec::new();
-            }
+    fn handle_connect(packet: &IncomingPacket, connection_client_id: Option<&str>, broker: &mut Broker) -> Result<Vec<u8>, &'static str> {
+        let IncomingPacket::Connect(request) = packet else {
+            return Err("Expected a CONNECT packet");
         };
-        let client_id = connect_payload.client_id.unwrap().clone(); 
-        if broker.is_client_connected(&client_id) {
-            error!("Client already connected...client will be removed");
-            broker.remove_client(&client_id);
-            return Vec::new();
-        }
-        broker.add_client(&client_id, connect.variable_header.keep_alive);
-        info!("Client connected: with id: [{}]", client_id);
-        //TODO: Send CONNACK packet
-        let ack_fixed_header = MqttHeaders::new(MqttPacketType::ConnAck, 0b0000, 2);
-        
-        let (session_present, return_code) = if connect.variable_header.connect_flags & 0b00000010 == 1 {
-            (false, 0b00000000)
-        } else {
-            (true, 0b00000000) // TODO: check doku and make more checks here
-        };
-        
-        let ack_variable_header = ConnAckHeader::new(session_present, return_code);
-        
-        let connack = ConnAck::new(ack_fixed_header, ack_variable_header, Payload::Default(Default::default()));
-        let connack_packet = connack.to_bytes();
-        connack_packet
+        // A second CONNECT on the same connection is a protocol violation [MQTT-3.1.0-2]
+        if connection_client_id.is_some() {
+            error!("Client sent a second CONNECT packet!");
+            return Err("Second CONNECT packet on the connection");
+        }
+        // An unsupported protocol level is answered with return code 0x01 before closing the connection [MQTT-3.1.2-2]
+        if request.protocol_level != Self::SUPPORTED_PROTOCOL_LEVEL {
+            error!("Client requested unsupported protocol level {}", request.protocol_level);
+            return Ok(ConnAck::new_rejected(ConnAckReturnCode::UnacceptableProtocolVersion).to_bytes());
+        }
+        if let Some(rejection) = Self::check_connect_lengths(request, broker.config()) {
+            return rejection;
+        }
+        // The connection assigns an id to a clean session connecting with an empty one, a client that wants
+        // its session kept has to identify itself [MQTT-3.1.3-8]. The id also stays empty when none was free.
+        if request.client_id.is_empty() {
+            error!("Client connected with an empty client id");
+            return Ok(ConnAck::new_rejected(ConnAckReturnCode::IdentifierRejected).to_bytes());
+        }
+        let client_id = &request.client_id;
+        if let Err(return_code) = broker.authenticate(client_id, request.username.as_deref(), request.password.as_deref()) {
+            warn!("Client [{}] failed authentication with return code {}", client_id, return_code);
+            let return_code = ConnAckReturnCode::from_u8(return_code).unwrap_or(ConnAckReturnCode::NotAuthorized);
+            return Ok(ConnAck::new_rejected(return_code).to_bytes());
+        }
+        if broker.is_client_connected(client_id) {
+            warn!("Client [{}] already connected, closing the old connection", client_id);
+            broker.take_over_client(client_id);
+        }
+        let session_present = broker.connect_client(client_id, request.keep_alive, request.clean_session);
+        if let Some(client) = broker.get_client_mut(client_id) {
+            client.will = request.will.clone();
+        }
+        info!("Client connected: with id: [{}], session present: {}", client_id, session_present);
+
+        let connack = ConnAck::new_success(session_present);
+        Ok(connack.to_bytes())
+    }
+
+    // The length prefixes in the CONNECT payload allow strings of up to 65535 bytes each,
+    // so cap them before the client is registered
+    fn check_connect_lengths(request: &ConnectRequest, config: &BrokerConfig) -> Option<Result<Vec<u8>, &'static str>> {
+        if request.client_id.len() > config.max_client_id_length {
+            error!("Client ID is longer than {} bytes", config.max_client_id_length);
+            return Some(Ok(ConnAck::new_rejected(ConnAckReturnCode::IdentifierRejected).to_bytes()));
+        }
+        let username_too_long = request.username.as_ref().is_some_and(|username| username.len() > config.max_username_length);
+        let password_too_long = request.password.as_ref().is_some_and(|password| password.len() > config.max_password_length);
+        if username_too_long || password_too_long {
+            error!("User name or password exceeds the configured length");
+            return Some(Ok(ConnAck::new_rejected(ConnAckReturnCode::BadCredentials).to_bytes()));
+        }
+        if let Some(will) = &request.will {
+            if will.topic.len() > config.max_will_topic_length || will.message.len() > config.max_will_message_length {
+                // there is no CONNACK return code for an oversized will, so just close the connection
+                error!("Will topic or message exceeds the configured length");
+                return Some(Err("Will exceeds the configured length"));
+            }
+        }
+        None
     }
 
-    fn handle_connack(data: &Vec<u8>, broker: &mut Broker) -> Vec<u8> {
-        // Empty function for ConnAck packet
-        let packet = Vec::new();
+    // CONNACK, SUBACK, UNSUBACK and PINGRESP are only ever sent by the server.
+    // A client sending one of them is a protocol violation and the connection is closed.
+    fn handle_connack(_packet: &IncomingPacket, _client_id: Option<&str>, _broker: &mut Broker) -> Result<Vec<u8>, &'static str> {
         error!("ConnAck packet not a recive packet for server!");
-        packet
+        Err("ConnAck packet not a recive packet for server")
+    }
+
+    // Forwards the message to every subscriber of its topic. Nothing is sent back to a QoS 0 publisher,
+    // a QoS 1 publisher gets a PUBACK once the message has been handed to the subscribers.
+    // A QoS 2 message is held back and answered with a PUBREC, it is only forwarded on the PUBREL.
+    fn handle_publish(packet: &IncomingPacket, client_id: Option<&str>, broker: &mut Broker) -> Result<Vec<u8>, &'static str> {
+        let IncomingPacket::Publish(publish) = packet else {
+            return Err("Expected a PUBLISH packet");
+        };
+        // The first packet on a connection must be a CONNECT [MQTT-3.1.0-1], so nothing is routed or retained
+        // for a client that has not been accepted yet
+        let Some(client_id) = client_id else {
+            error!("PUBLISH received before CONNECT!");
+            return Err("PUBLISH received before CONNECT");
+        };
+        let topic = &publish.variable_header.topic_name;
+        if !is_valid_topic_name(topic) {
+            error!("PUBLISH from [{}] to invalid topic name [{}]", client_id, topic);
+            return Err("PUBLISH to an invalid topic name");
+        }
+        let packet_id = publish.variable_header.packet_id;
+        if publish.qos() == 2 {
+            // retained together with the delivery once the PUBREL arrives
+            let message = HeldMessage { topic: topic.clone(), payload: publish.payload_bytes().to_vec(), retain: publish.retain() };
+            if !broker.hold_for_release(client_id, packet_id, message) {
+                info!("QoS 2 message {} from [{}] is already held, not storing it again", packet_id, client_id);
+            }
+            return Ok(PubRec::new_for(packet_id).to_bytes());
+        }
+        if publish.retain() {
+            broker.retain(topic, publish.payload_bytes());
+        }
+        Self::route_publish(broker, topic, publish.payload_bytes());
+        match publish.qos() {
+            1 => Ok(PubAck::new_for(packet_id).to_bytes()),
+            _ => Ok(Vec::new()),
+        }
     }
 
-    fn handle_publish(data: &Vec<u8>, broker: &mut Broker) -> Vec<u8> {
-        // Empty function for Publish packet
-        let packet = Vec::new();
-        packet
+    fn route_publish(broker: &mut Broker, topic: &str, payload: &[u8]) {
+        let delivery = Publish::for_delivery(topic, payload).to_bytes();
+        let delivered = broker.route(topic, &delivery);
+        info!("Routed message on [{}] to {} subscribers", topic, delivered);
     }
 
-    fn handle_puback(data: &Vec<u8>, broker: &mut Broker) -> Vec<u8> {
+    fn handle_puback(_packet: &IncomingPacket, _client_id: Option<&str>, _broker: &mut Broker) -> Result<Vec<u8>, &'static str> {
         // Empty function for PubAck packet
-        let packet = Vec::new();
-        packet
+        Ok(Vec::new())
     }
 
-    fn handle_pubrec(data: &Vec<u8>, broker: &mut Broker) -> Vec<u8> {
+    fn handle_pubrec(_packet: &IncomingPacket, _client_id: Option<&str>, _broker: &mut Broker) -> Result<Vec<u8>, &'static str> {
         // Empty function for PubRec packet
-        let packet = Vec::new();
-        packet
+        Ok(Vec::new())
     }
 
-    fn handle_pubrel(data: &Vec<u8>, broker: &mut Broker) -> Vec<u8> {
-        // Empty function for PubRel packet
-        let packet = Vec::new();
-        packet
+    // Releases the held QoS 2 message to the subscribers and completes the handshake with a PUBCOMP
+    fn handle_pubrel(packet: &IncomingPacket, client_id: Option<&str>, broker: &mut Broker) -> Result<Vec<u8>, &'static str> {
+        let IncomingPacket::PubRel { packet_id } = packet else {
+            return Err("Expected a PUBREL packet");
+        };
+        let Some(client_id) = client_id else {
+            error!("PUBREL received before CONNECT!");
+            return Err("PUBREL received before CONNECT");
+        };
+        match broker.release(client_id, *packet_id) {
+            Some(message) => {
+                if message.retain {
+                    broker.retain(&message.topic, &message.payload);
+                }
+                Self::route_publish(broker, &message.topic, &message.payload)
+            }
+            // The message was already released, e.g. the PUBCOMP got lost and the client retransmitted the PUBREL.
+            // It is still completed so the client can free the packet id [MQTT-4.3.3]
+            None => warn!("PUBREL from [{}] for unknown packet id {}", client_id, packet_id),
+        }
+        Ok(PubComp::new_for(*packet_id).to_bytes())
     }
 
-    fn handle_pubcomp(data: &Vec<u8>, broker: &mut Broker) -> Vec<u8> {
+    fn handle_pubcomp(_packet: &IncomingPacket, _client_id: Option<&str>, _broker: &mut Broker) -> Result<Vec<u8>, &'static str> {
         // Empty function for PubComp packet
-        let packet = Vec::new();
-        packet
+        Ok(Vec::new())
     }
 
-    fn handle_subscribe(data: &Vec<u8>, broker: &mut Broker) -> Vec<u8> {
-        // Empty function for Subscribe packet
-        let packet = Vec::new();
-        packet
+    // Registers each topic filter for the client and acknowledges them with one return code per filter,
+    // granting the broker's maximum QoS
+    fn handle_subscribe(packet: &IncomingPacket, client_id: Option<&str>, broker: &mut Broker) -> Result<Vec<u8>, &'static str> {
+        let IncomingPacket::Subscribe(subscribe) = packet else {
+            return Err("Expected a SUBSCRIBE packet");
+        };
+        let Some(client_id) = client_id else {
+            error!("SUBSCRIBE received before CONNECT!");
+            return Err("SUBSCRIBE received before CONNECT");
+        };
+        let return_codes = subscribe
+            .topic_filters()
+            .into_iter()
+            .map(|(topic_filter, qos)| {
+                if qos > 2 {
+                    error!("Invalid QoS {} requested for [{}]", qos, topic_filter);
+                    return SubAck::FAILURE_RETURN_CODE;
+                }
+                if !is_valid_filter(&topic_filter) {
+                    error!("Invalid topic filter [{}]", topic_filter);
+                    return SubAck::FAILURE_RETURN_CODE;
+                }
+                broker.add_subscription(client_id, &topic_filter);
+                info!("Client [{}] subscribed to [{}]", client_id, topic_filter);
+                // Queued on the client's connection right away, while the SUBACK is only queued once the
+                // handler returns. The client therefore gets the retained messages before the SUBACK,
+                // which the protocol allows.
+                for (topic, payload) in broker.retained_matching(&topic_filter) {
+                    broker.send_to(client_id, &Publish::for_retained_delivery(&topic, &payload).to_bytes());
+                }
+                Self::MAX_GRANTED_QOS
+            })
+            .collect();
+        Ok(SubAck::new_granted(subscribe.variable_header.packet_id, return_codes).to_bytes())
     }
 
-    fn handle_suback(data: &Vec<u8>, broker: &mut Broker) -> Vec<u8> {
-        // Empty function for SubAck packet
-        let packet = Vec::new();
-        packet
+    fn handle_suback(_packet: &IncomingPacket, _client_id: Option<&str>, _broker: &mut Broker) -> Result<Vec<u8>, &'static str> {
+        error!("SubAck packet not a recive packet for server!");
+        Err("SubAck packet not a recive packet for server")
     }
 
-    fn handle_unsubscribe(data: &Vec<u8>, broker: &mut Broker) -> Vec<u8> {
+    fn handle_unsubscribe(_packet: &IncomingPacket, _client_id: Option<&str>, _broker: &mut Broker) -> Result<Vec<u8>, &'static str> {
         // Empty function for Unsubscribe packet
-        let packet = Vec::new();
-        packet
+        Ok(Vec::new())
+    }
+
+    fn handle_unsuback(_packet: &IncomingPacket, _client_id: Option<&str>, _broker: &mut Broker) -> Result<Vec<u8>, &'static str> {
+        error!("UnsubAck packet not a recive packet for server!");
+        Err("UnsubAck packet not a recive packet for server")
+    }
+
+    // The server MUST send a PINGRESP in response to a PINGREQ [MQTT-3.12.4-1]
+    fn handle_ping_req(_packet: &IncomingPacket, client_id: Option<&str>, broker: &mut Broker) -> Result<Vec<u8>, &'static str> {
+        let Some(client_id) = client_id else {
+            error!("PINGREQ received before CONNECT!");
+            return Err("PINGREQ received before CONNECT");
+        };
+        broker.update_client_activity(client_id);
+        Ok(MqttHeaders::new(MqttPacketType::PingResp, 0, 0).to_bytes())
+    }
+
+    fn handle_ping_resp(_packet: &IncomingPacket, _client_id: Option<&str>, _broker: &mut Broker) -> Result<Vec<u8>, &'static str> {
+        error!("PingResp packet not a recive packet for server!");
+        Err("PingResp packet not a recive packet for server")
+    }
+
+    // The connection loop closes the network connection after a DISCONNECT [MQTT-3.14.4-1],
+    // here the session is ended and the will discarded
+    fn handle_disconnect(_packet: &IncomingPacket, client_id: Option<&str>, broker: &mut Broker) -> Result<Vec<u8>, &'static str> {
+        match client_id {
+            Some(client_id) if broker.disconnect_client(client_id) => info!("Client disconnected: with id: [{}]", client_id),
+            Some(client_id) => warn!("DISCONNECT from unknown client [{}]", client_id),
+            None => warn!("DISCONNECT received before CONNECT"),
+        }
+        Ok(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod dispatcher_tests {
+    use super::*;
+    use crate::models::auth::Authenticator;
+    use crate::models::decode::decode;
+    #[cfg(feature = "statsd")]
+    use crate::models::metrics::StatsdSink;
+    use crate::models::mqtt_headers::PublishHeader;
+    use crate::models::mqtt_payloads::{Payload, PublishPayload};
+    use crate::models::test_packets::{connect_packet, length_prefixed};
+    use tokio::sync::mpsc;
+    use tokio_tungstenite::tungstenite::protocol::Message;
+    use std::sync::{Arc, Mutex};
+
+    fn dispatch(packet_type: MqttPacketType, data: &[u8]) -> Result<Vec<u8>, &'static str> {
+        dispatch_with(&mut Broker::new(), packet_type, data)
+    }
+
+    fn dispatch_with(broker: &mut Broker, packet_type: MqttPacketType, data: &[u8]) -> Result<Vec<u8>, &'static str> {
+        let dispatcher = MqttPacketDispatcher::new().unwrap();
+        let handler = dispatcher.handlers.get(&packet_type).unwrap();
+        handler(&decode(data).map_err(|_| "Malformed packet")?, None, broker)
+    }
+
+    fn dispatch_as(broker: &mut Broker, client_id: &str, packet_type: MqttPacketType, data: &[u8]) -> Result<Vec<u8>, &'static str> {
+        let dispatcher = MqttPacketDispatcher::new().unwrap();
+        let handler = dispatcher.handlers.get(&packet_type).unwrap();
+        handler(&decode(data).map_err(|_| "Malformed packet")?, Some(client_id), broker)
+    }
+
+    // PUBLISH from the accepted client "pub"
+    fn publish(broker: &mut Broker, data: &[u8]) -> Result<Vec<u8>, &'static str> {
+        if broker.get_client("pub").is_none() {
+            broker.add_client("pub", 60);
+        }
+        dispatch_as(broker, "pub", MqttPacketType::Publish, data)
+    }
+
+    #[test]
+    fn test_server_only_packets_are_rejected() {
+        assert!(dispatch(MqttPacketType::ConnAck, &[0x20, 0x02, 0x00, 0x00]).is_err());
+        assert!(dispatch(MqttPacketType::SubAck, &[0x90, 0x03, 0x00, 0x01, 0x00]).is_err());
+        assert!(dispatch(MqttPacketType::UnsubAck, &[0xB0, 0x02, 0x00, 0x01]).is_err());
+        assert!(dispatch(MqttPacketType::PingResp, &[0xD0, 0x00]).is_err());
+    }
+
+    #[test]
+    fn test_register_custom_publish_handler() {
+        let topics = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&topics);
+        let mut dispatcher = MqttPacketDispatcher::new().unwrap();
+        let default_handler = dispatcher.handlers.remove(&MqttPacketType::Publish).unwrap();
+        dispatcher.register(MqttPacketType::Publish, Box::new(move |packet: &IncomingPacket, client_id: Option<&str>, broker: &mut Broker| {
+            if let IncomingPacket::Publish(publish) = packet {
+                recorded.lock().unwrap().push(publish.variable_header.topic_name.clone());
+            }
+            default_handler(packet, client_id, broker)
+        }));
+
+        let mut broker = Broker::new();
+        broker.add_client("pub", 60);
+        let handler = dispatcher.handlers.get(&MqttPacketType::Publish).unwrap();
+        // PUBLISH QoS 0 to topic "a/b" with payload "hi"
+        let packet = decode(&[0x30, 0x07, 0x00, 0x03, 0x61, 0x2F, 0x62, 0x68, 0x69]).unwrap();
+        let result = handler(&packet, Some("pub"), &mut broker);
+        assert_eq!(result, Ok(Vec::new()));
+        assert_eq!(*topics.lock().unwrap(), vec!["a/b".to_string()]);
+    }
+
+    #[test]
+    fn test_register_returns_previous_handler() {
+        let mut dispatcher = MqttPacketDispatcher::new().unwrap();
+        let previous = dispatcher.register(MqttPacketType::PingReq, Box::new(|_: &IncomingPacket, _: Option<&str>, _: &mut Broker| Ok(vec![0xD0, 0x00])));
+        assert!(previous.is_some());
+        let handler = dispatcher.handlers.get(&MqttPacketType::PingReq).unwrap();
+        assert_eq!(handler(&IncomingPacket::PingReq, None, &mut Broker::new()), Ok(vec![0xD0, 0x00]));
+    }
+
+    #[test]
+    fn test_header_only_connect_is_rejected() {
+        assert!(dispatch(MqttPacketType::Connect, &[0x10, 0x00]).is_err());
+    }
+
+    #[test]
+    fn test_connect_within_length_caps_is_accepted() {
+        let mut broker = Broker::new();
+        let response = dispatch_with(&mut broker, MqttPacketType::Connect, &connect_packet("test", 0x82, &length_prefixed("user")));
+        assert_eq!(response, Ok(vec![0x20, 0x02, 0x00, 0x00]));
+        assert!(broker.is_client_connected("test"));
+    }
+
+    #[test]
+    fn test_connect_username_over_cap_is_rejected() {
+        let mut broker = Broker::with_config(BrokerConfig { max_username_length: 4, ..BrokerConfig::default() });
+        let response = dispatch_with(&mut broker, MqttPacketType::Connect, &connect_packet("test", 0x82, &length_prefixed("toolong")));
+        assert_eq!(response, Ok(vec![0x20, 0x02, 0x00, ConnAckReturnCode::BadCredentials.to_u8()]));
+        assert!(!broker.is_client_connected("test"));
+    }
+
+    #[test]
+    fn test_connect_client_id_over_cap_is_rejected() {
+        let mut broker = Broker::with_config(BrokerConfig { max_client_id_length: 3, ..BrokerConfig::default() });
+        let response = dispatch_with(&mut broker, MqttPacketType::Connect, &connect_packet("test", 0x02, &[]));
+        assert_eq!(response, Ok(vec![0x20, 0x02, 0x00, ConnAckReturnCode::IdentifierRejected.to_u8()]));
+        assert!(!broker.is_client_connected("test"));
+    }
+
+    #[test]
+    fn test_connect_will_over_cap_closes_connection() {
+        let mut broker = Broker::with_config(BrokerConfig { max_will_message_length: 2, ..BrokerConfig::default() });
+        let will = [length_prefixed("a/b"), length_prefixed("bye")].concat();
+        assert!(dispatch_with(&mut broker, MqttPacketType::Connect, &connect_packet("test", 0x06, &will)).is_err());
+        assert!(!broker.is_client_connected("test"));
+    }
+
+    #[test]
+    fn test_zero_packet_id_is_rejected() {
+        // SUBSCRIBE "a" QoS 0 with packet id 0
+        assert!(dispatch(MqttPacketType::Subscribe, &[0x82, 0x06, 0x00, 0x00, 0x00, 0x01, 0x61, 0x00]).is_err());
+        // UNSUBSCRIBE "a" with packet id 0
+        assert!(dispatch(MqttPacketType::Unsubscribe, &[0xA2, 0x05, 0x00, 0x00, 0x00, 0x01, 0x61]).is_err());
+        // QoS 1 PUBLISH to "a" with packet id 0
+        assert!(publish(&mut Broker::new(), &[0x32, 0x05, 0x00, 0x01, 0x61, 0x00, 0x00]).is_err());
+    }
+
+    #[test]
+    fn test_non_zero_packet_id_is_accepted() {
+        let mut broker = Broker::new();
+        broker.add_client("sub", 60);
+        assert!(dispatch_as(&mut broker, "sub", MqttPacketType::Subscribe, &[0x82, 0x06, 0x00, 0x01, 0x00, 0x01, 0x61, 0x00]).is_ok());
+        assert!(dispatch(MqttPacketType::Unsubscribe, &[0xA2, 0x05, 0x00, 0x01, 0x00, 0x01, 0x61]).is_ok());
+        // a QoS 0 PUBLISH has no packet id at all
+        assert!(publish(&mut Broker::new(), &[0x30, 0x05, 0x00, 0x01, 0x61, 0x68, 0x69]).is_ok());
+    }
+
+    #[test]
+    fn test_publish_handler_without_bytes() {
+        let publish = Publish::new(
+            MqttHeaders::new(MqttPacketType::Publish, 0, 5),
+            PublishHeader { topic_name: "a".to_string(), packet_id: 0 },
+            Payload::Publish(PublishPayload { payload: b"hi".to_vec() }),
+        );
+        let mut broker = Broker::new();
+        broker.add_client("pub", 60);
+        let result = MqttPacketDispatcher::handle_publish(&IncomingPacket::Publish(publish), Some("pub"), &mut broker);
+        assert_eq!(result, Ok(Vec::new()));
     }
 
-    fn handle_unsuback(data: &Vec<u8>, broker: &mut Broker) -> Vec<u8> {
-        // Empty function for UnsubAck packet
-        let packet = Vec::new();
-        packet
+    #[test]
+    fn test_publish_before_connect_is_rejected() {
+        let mut broker = Broker::new();
+        let mut subscriber = subscribed_client(&mut broker, "sub", &["a"]);
+        // retained QoS 1 PUBLISH to "a" with packet id 0x000A and payload "hi"
+        let response = dispatch_with(&mut broker, MqttPacketType::Publish, &[0x33, 0x07, 0x00, 0x01, 0x61, 0x00, 0x0A, 0x68, 0x69]);
+        assert_eq!(response, Err("PUBLISH received before CONNECT"));
+        assert!(subscriber.try_recv().is_err());
+        assert_eq!(broker.retained_message("a"), None);
+    }
+
+    #[test]
+    fn test_publish_to_invalid_topic_name_is_rejected() {
+        let mut broker = Broker::new();
+        let mut subscriber = subscribed_client(&mut broker, "sub", &["#"]);
+        // retained QoS 0 PUBLISH with payload "hi" to "a/+", "#" and ""
+        for data in [&[0x31, 0x07, 0x00, 0x03, 0x61, 0x2F, 0x2B, 0x68, 0x69][..], &[0x31, 0x05, 0x00, 0x01, 0x23, 0x68, 0x69], &[0x31, 0x04, 0x00, 0x00, 0x68, 0x69]] {
+            assert_eq!(publish(&mut broker, data), Err("PUBLISH to an invalid topic name"));
+        }
+        assert!(subscriber.try_recv().is_err());
+        assert_eq!(broker.retained_message("a/+"), None);
+        assert_eq!(broker.retained_message("#"), None);
+        assert_eq!(broker.retained_message(""), None);
+    }
+
+    // Connects a client subscribed to the given topics and returns the receiving end of its outbound queue
+    fn subscribed_client(broker: &mut Broker, client_id: &str, topics: &[&str]) -> mpsc::Receiver<Message> {
+        let (sender, receiver) = mpsc::channel(8);
+        broker.add_client(client_id, 60);
+        broker.set_client_sender(client_id, sender);
+        for topic in topics {
+            broker.add_subscription(client_id, topic);
+        }
+        receiver
+    }
+
+    #[test]
+    fn test_publish_is_routed_to_subscribers() {
+        let mut broker = Broker::new();
+        let mut subscriber = subscribed_client(&mut broker, "sub", &["a/b"]);
+        let mut other = subscribed_client(&mut broker, "other", &["c"]);
+        // PUBLISH QoS 1 to topic "a/b" with packet id 10 and payload "hi"
+        let response = publish(&mut broker, &[0x32, 0x09, 0x00, 0x03, 0x61, 0x2F, 0x62, 0x00, 0x0A, 0x68, 0x69]);
+        assert_eq!(response, Ok(vec![0x40, 0x02, 0x00, 0x0A]));
+        assert_eq!(subscriber.try_recv().unwrap(), Message::Binary(vec![0x30, 0x07, 0x00, 0x03, 0x61, 0x2F, 0x62, 0x68, 0x69]));
+        assert!(other.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_publish_acknowledgement_depends_on_qos() {
+        // QoS 0 PUBLISH to "a" with payload "hi"
+        assert_eq!(publish(&mut Broker::new(), &[0x30, 0x05, 0x00, 0x01, 0x61, 0x68, 0x69]), Ok(Vec::new()));
+        // QoS 1 PUBLISH to "a" with packet id 0x000A and payload "hi"
+        assert_eq!(publish(&mut Broker::new(), &[0x32, 0x07, 0x00, 0x01, 0x61, 0x00, 0x0A, 0x68, 0x69]), Ok(vec![0x40, 0x02, 0x00, 0x0A]));
+    }
+
+    #[test]
+    fn test_qos2_handshake_delivers_on_pubrel() {
+        let mut broker = Broker::new();
+        broker.add_client("pub", 60);
+        let mut subscriber = subscribed_client(&mut broker, "sub", &["a"]);
+        // QoS 2 PUBLISH to "a" with packet id 0x000A and payload "hi"
+        let publish = [0x34, 0x07, 0x00, 0x01, 0x61, 0x00, 0x0A, 0x68, 0x69];
+        assert_eq!(dispatch_as(&mut broker, "pub", MqttPacketType::Publish, &publish), Ok(vec![0x50, 0x02, 0x00, 0x0A]));
+        assert!(subscriber.try_recv().is_err());
+
+        assert_eq!(dispatch_as(&mut broker, "pub", MqttPacketType::PubRel, &[0x62, 0x02, 0x00, 0x0A]), Ok(vec![0x70, 0x02, 0x00, 0x0A]));
+        assert_eq!(subscriber.try_recv().unwrap(), Message::Binary(Publish::for_delivery("a", b"hi").to_bytes()));
+    }
+
+    #[test]
+    fn test_qos2_retained_publish_is_stored_on_pubrel() {
+        let mut broker = Broker::new();
+        // retained QoS 2 PUBLISH to "a" with packet id 0x000A and payload "hi"
+        publish(&mut broker, &[0x35, 0x07, 0x00, 0x01, 0x61, 0x00, 0x0A, 0x68, 0x69]).unwrap();
+        assert_eq!(broker.retained_message("a"), None);
+
+        dispatch_as(&mut broker, "pub", MqttPacketType::PubRel, &[0x62, 0x02, 0x00, 0x0A]).unwrap();
+        assert_eq!(broker.retained_message("a"), Some(&b"hi"[..]));
+    }
+
+    #[test]
+    fn test_qos2_retransmitted_publish_is_delivered_once() {
+        let mut broker = Broker::new();
+        broker.add_client("pub", 60);
+        let mut subscriber = subscribed_client(&mut broker, "sub", &["a"]);
+        let publish = [0x34, 0x07, 0x00, 0x01, 0x61, 0x00, 0x0A, 0x68, 0x69];
+        // the retransmission has the DUP flag set and is acknowledged again
+        let retransmission = [0x3C, 0x07, 0x00, 0x01, 0x61, 0x00, 0x0A, 0x68, 0x69];
+        dispatch_as(&mut broker, "pub", MqttPacketType::Publish, &publish).unwrap();
+        assert_eq!(dispatch_as(&mut broker, "pub", MqttPacketType::Publish, &retransmission), Ok(vec![0x50, 0x02, 0x00, 0x0A]));
+        dispatch_as(&mut broker, "pub", MqttPacketType::PubRel, &[0x62, 0x02, 0x00, 0x0A]).unwrap();
+
+        assert!(subscriber.try_recv().is_ok());
+        assert!(subscriber.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_pubrel_for_unknown_packet_id_is_completed() {
+        let mut broker = Broker::new();
+        broker.add_client("pub", 60);
+        let mut subscriber = subscribed_client(&mut broker, "sub", &["a"]);
+        assert_eq!(dispatch_as(&mut broker, "pub", MqttPacketType::PubRel, &[0x62, 0x02, 0x00, 0x0B]), Ok(vec![0x70, 0x02, 0x00, 0x0B]));
+        assert!(subscriber.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_publish_is_routed_to_wildcard_subscribers() {
+        let mut broker = Broker::new();
+        let mut single_level = subscribed_client(&mut broker, "single", &["+/b"]);
+        let mut multi_level = subscribed_client(&mut broker, "multi", &["a/#"]);
+        let mut both = subscribed_client(&mut broker, "both", &["a/b", "a/+"]);
+        publish(&mut broker, &[0x30, 0x07, 0x00, 0x03, 0x61, 0x2F, 0x62, 0x68, 0x69]).unwrap();
+        assert!(single_level.try_recv().is_ok());
+        assert!(multi_level.try_recv().is_ok());
+        // overlapping subscriptions of one client still deliver a single copy
+        assert!(both.try_recv().is_ok());
+        assert!(both.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_deliveries_keep_publish_order_across_topics() {
+        let mut broker = Broker::new();
+        let mut subscriber = subscribed_client(&mut broker, "sub", &["a/+"]);
+        let published: Vec<(&str, u8)> = vec![("a/1", b'0'), ("a/2", b'1'), ("a/1", b'2'), ("a/2", b'3'), ("a/1", b'4')];
+        for (topic, payload) in &published {
+            let packet = Publish::for_delivery(topic, &[*payload]).to_bytes();
+            publish(&mut broker, &packet).unwrap();
+        }
+        for (topic, payload) in published {
+            assert_eq!(subscriber.try_recv().unwrap(), Message::Binary(Publish::for_delivery(topic, &[payload]).to_bytes()));
+        }
+        assert!(subscriber.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_retained_publish_is_stored_and_cleared() {
+        let mut broker = Broker::new();
+        // QoS 0 PUBLISH with RETAIN to "a" with payload "hi"
+        publish(&mut broker, &[0x31, 0x05, 0x00, 0x01, 0x61, 0x68, 0x69]).unwrap();
+        assert_eq!(broker.retained_message("a"), Some(&b"hi"[..]));
+        // a retained PUBLISH with an empty payload removes it
+        publish(&mut broker, &[0x31, 0x03, 0x00, 0x01, 0x61]).unwrap();
+        assert_eq!(broker.retained_message("a"), None);
+    }
+
+    #[test]
+    fn test_retained_message_is_sent_on_subscribe() {
+        let mut broker = Broker::new();
+        publish(&mut broker, &[0x31, 0x05, 0x00, 0x01, 0x61, 0x68, 0x69]).unwrap();
+        let mut subscriber = subscribed_client(&mut broker, "sub", &[]);
+        // SUBSCRIBE packet id 1 to "+" with QoS 0
+        let response = dispatch_as(&mut broker, "sub", MqttPacketType::Subscribe, &[0x82, 0x06, 0x00, 0x01, 0x00, 0x01, 0x2B, 0x00]);
+        assert_eq!(response, Ok(vec![0x90, 0x03, 0x00, 0x01, 0x00]));
+        assert_eq!(subscriber.try_recv().unwrap(), Message::Binary(vec![0x31, 0x05, 0x00, 0x01, 0x61, 0x68, 0x69]));
+    }
+
+    #[test]
+    fn test_publish_without_subscribers_is_dropped() {
+        let mut broker = Broker::new();
+        let response = publish(&mut broker, &[0x30, 0x05, 0x00, 0x01, 0x61, 0x68, 0x69]);
+        assert_eq!(response, Ok(Vec::new()));
+        assert_eq!(broker.stats().messages_sent, 0);
+    }
+
+    #[test]
+    fn test_publish_to_client_subscribed_twice_is_delivered_once() {
+        let mut broker = Broker::new();
+        let mut subscriber = subscribed_client(&mut broker, "sub", &["a", "a"]);
+        publish(&mut broker, &[0x30, 0x05, 0x00, 0x01, 0x61, 0x68, 0x69]).unwrap();
+        assert!(subscriber.try_recv().is_ok());
+        assert!(subscriber.try_recv().is_err());
     }
 
-    fn handle_ping_req(data: &Vec<u8>, broker: &mut Broker) -> Vec<u8> {
-        // Empty function for PingReq packet
-        let packet = Vec::new();
-        packet
+    #[test]
+    fn test_subscribe_is_acknowledged_with_granted_qos() {
+        let mut broker = Broker::new();
+        broker.add_client("sub", 60);
+        // SUBSCRIBE packet id 0x1234 to "a/b" with QoS 2
+        let response = dispatch_as(&mut broker, "sub", MqttPacketType::Subscribe, &[0x82, 0x08, 0x12, 0x34, 0x00, 0x03, 0x61, 0x2F, 0x62, 0x02]);
+        assert_eq!(response, Ok(vec![0x90, 0x03, 0x12, 0x34, 0x00]));
+        assert!(broker.get_client("sub").unwrap().subscriptions.contains("a/b"));
     }
 
-    fn handle_ping_resp(data: &Vec<u8>, broker: &mut Broker) -> Vec<u8> {
-        // Empty function for PingResp packet
-        let packet = Vec::new();
-        packet
+    #[test]
+    fn test_subscribe_to_several_filters() {
+        let mut broker = Broker::new();
+        broker.add_client("sub", 60);
+        // SUBSCRIBE packet id 1 to "a" with QoS 0, "b" with QoS 3 and "c" with QoS 1
+        let data = [0x82, 0x0E, 0x00, 0x01, 0x00, 0x01, 0x61, 0x00, 0x00, 0x01, 0x62, 0x03, 0x00, 0x01, 0x63, 0x01];
+        let response = dispatch_as(&mut broker, "sub", MqttPacketType::Subscribe, &data);
+        assert_eq!(response, Ok(vec![0x90, 0x05, 0x00, 0x01, 0x00, SubAck::FAILURE_RETURN_CODE, 0x00]));
+        let subscriptions = &broker.get_client("sub").unwrap().subscriptions;
+        assert!(subscriptions.contains("a") && subscriptions.contains("c") && !subscriptions.contains("b"));
     }
 
-    fn handle_disconnect(data: &Vec<u8>, broker: &mut Broker) -> Vec<u8> {
-        // Empty function for Disconnect packet
-        let packet = Vec::new();
-        packet
+    #[test]
+    fn test_subscribe_with_invalid_qos_fails() {
+        let mut broker = Broker::new();
+        broker.add_client("sub", 60);
+        let response = dispatch_as(&mut broker, "sub", MqttPacketType::Subscribe, &[0x82, 0x06, 0x00, 0x01, 0x00, 0x01, 0x61, 0x03]);
+        assert_eq!(response, Ok(vec![0x90, 0x03, 0x00, 0x01, SubAck::FAILURE_RETURN_CODE]));
+        assert!(broker.get_client("sub").unwrap().subscriptions.is_empty());
+    }
+
+    #[test]
+    fn test_subscribe_with_invalid_filter_fails() {
+        let mut broker = Broker::new();
+        broker.add_client("sub", 60);
+        // SUBSCRIBE packet id 1 to "a#" with QoS 0
+        let response = dispatch_as(&mut broker, "sub", MqttPacketType::Subscribe, &[0x82, 0x07, 0x00, 0x01, 0x00, 0x02, 0x61, 0x23, 0x00]);
+        assert_eq!(response, Ok(vec![0x90, 0x03, 0x00, 0x01, SubAck::FAILURE_RETURN_CODE]));
+    }
+
+    #[test]
+    fn test_subscribe_before_connect_is_rejected() {
+        assert!(dispatch(MqttPacketType::Subscribe, &[0x82, 0x06, 0x00, 0x01, 0x00, 0x01, 0x61, 0x00]).is_err());
+    }
+
+    #[test]
+    fn test_ping_req_is_answered_with_ping_resp() {
+        let mut broker = Broker::new();
+        broker.add_client("test", 60);
+        assert_eq!(dispatch_as(&mut broker, "test", MqttPacketType::PingReq, &[0xC0, 0x00]), Ok(vec![0xD0, 0x00]));
+    }
+
+    #[test]
+    fn test_ping_req_before_connect_is_rejected() {
+        assert!(dispatch(MqttPacketType::PingReq, &[0xC0, 0x00]).is_err());
+    }
+
+    #[test]
+    fn test_connect_takes_over_existing_client() {
+        let mut broker = Broker::new();
+        let mut old_connection = subscribed_client(&mut broker, "test", &[]);
+        let response = dispatch_with(&mut broker, MqttPacketType::Connect, &connect_packet("test", 0x02, &[]));
+        assert_eq!(response, Ok(vec![0x20, 0x02, 0x00, 0x00]));
+        assert_eq!(old_connection.try_recv().unwrap(), Message::Close(None));
+        assert!(broker.is_client_connected("test"));
+    }
+
+    #[test]
+    fn test_clean_session_connect_has_no_session_present() {
+        let mut broker = Broker::new();
+        dispatch_with(&mut broker, MqttPacketType::Connect, &connect_packet("test", 0x00, &[])).unwrap();
+        dispatch_as(&mut broker, "test", MqttPacketType::Disconnect, &[0xE0, 0x00]).unwrap();
+        // the stored session is discarded instead of reported
+        let response = dispatch_with(&mut broker, MqttPacketType::Connect, &connect_packet("test", 0x02, &[]));
+        assert_eq!(response, Ok(vec![0x20, 0x02, 0x00, 0x00]));
+    }
+
+    #[test]
+    fn test_persistent_connect_reports_stored_session() {
+        let mut broker = Broker::new();
+        let response = dispatch_with(&mut broker, MqttPacketType::Connect, &connect_packet("test", 0x00, &[]));
+        assert_eq!(response, Ok(vec![0x20, 0x02, 0x00, 0x00]));
+        broker.add_subscription("test", "a/b");
+        dispatch_as(&mut broker, "test", MqttPacketType::Disconnect, &[0xE0, 0x00]).unwrap();
+
+        let response = dispatch_with(&mut broker, MqttPacketType::Connect, &connect_packet("test", 0x00, &[]));
+        assert_eq!(response, Ok(vec![0x20, 0x02, 0x01, 0x00]));
+        assert!(broker.get_client("test").unwrap().subscriptions.contains("a/b"));
+    }
+
+    #[test]
+    fn test_connect_with_unsupported_protocol_level_is_rejected() {
+        let mut broker = Broker::new();
+        for protocol_level in [3, 5] {
+            // CONNECT with clean session and the client id "test", announcing the given protocol level
+            let data = [0x10, 0x10, 0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, protocol_level, 0x02, 0x00, 0x3C, 0x00, 0x04, 0x74, 0x65, 0x73, 0x74];
+            let response = dispatch_with(&mut broker, MqttPacketType::Connect, &data);
+            assert_eq!(response, Ok(vec![0x20, 0x02, 0x00, ConnAckReturnCode::UnacceptableProtocolVersion.to_u8()]));
+            assert!(!broker.is_client_connected("test"));
+        }
+    }
+
+    #[derive(Debug)]
+    struct RejectAll;
+
+    impl Authenticator for RejectAll {
+        fn authenticate(&self, _client_id: &str, _username: Option<&str>, _password: Option<&str>) -> Result<(), u8> {
+            Err(ConnAckReturnCode::NotAuthorized.to_u8())
+        }
+    }
+
+    #[test]
+    fn test_connect_rejected_by_authenticator() {
+        let mut broker = Broker::new();
+        broker.set_authenticator(Box::new(RejectAll));
+        let response = dispatch_with(&mut broker, MqttPacketType::Connect, &connect_packet("test", 0x82, &length_prefixed("user")));
+        assert_eq!(response, Ok(vec![0x20, 0x02, 0x00, 0x05]));
+        assert!(!broker.is_client_connected("test"));
+    }
+
+    #[test]
+    fn test_connect_with_empty_client_id_is_rejected() {
+        // CONNECT without clean session and a zero length client id
+        let data = [0x10, 0x0C, 0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, 0x04, 0x00, 0x00, 0x3C, 0x00, 0x00];
+        let response = dispatch(MqttPacketType::Connect, &data);
+        assert_eq!(response, Ok(vec![0x20, 0x02, 0x00, ConnAckReturnCode::IdentifierRejected.to_u8()]));
+    }
+
+    #[test]
+    fn test_second_connect_on_connection_is_rejected() {
+        let mut broker = Broker::new();
+        broker.add_client("test", 60);
+        assert!(dispatch_as(&mut broker, "test", MqttPacketType::Connect, &connect_packet("test", 0x02, &[])).is_err());
+    }
+
+    #[test]
+    fn test_disconnect_removes_clean_session() {
+        let mut broker = Broker::new();
+        dispatch_with(&mut broker, MqttPacketType::Connect, &connect_packet("test", 0x06, &[length_prefixed("a/b"), length_prefixed("bye")].concat())).unwrap();
+        assert_eq!(dispatch_as(&mut broker, "test", MqttPacketType::Disconnect, &[0xE0, 0x00]), Ok(Vec::new()));
+        assert!(broker.get_client("test").is_none());
+    }
+
+    #[test]
+    fn test_disconnect_keeps_persistent_session_without_will() {
+        let mut broker = Broker::new();
+        dispatch_with(&mut broker, MqttPacketType::Connect, &connect_packet("test", 0x04, &[length_prefixed("a/b"), length_prefixed("bye")].concat())).unwrap();
+        assert!(broker.get_client("test").unwrap().will.is_some());
+        dispatch_as(&mut broker, "test", MqttPacketType::Disconnect, &[0xE0, 0x00]).unwrap();
+        let client = broker.get_client("test").unwrap();
+        assert!(!client.is_connected());
+        assert!(client.will.is_none());
+        assert!(!broker.is_client_connected("test"));
+    }
+
+    #[test]
+    fn test_disconnect_from_unknown_client() {
+        assert_eq!(dispatch_as(&mut Broker::new(), "ghost", MqttPacketType::Disconnect, &[0xE0, 0x00]), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn test_client_packets_are_accepted() {
+        assert_eq!(dispatch(MqttPacketType::Disconnect, &[0xE0, 0x00]), Ok(Vec::new()));
+    }
+
+    #[cfg(feature = "statsd")]
+    #[test]
+    fn test_connect_and_publish_emit_statsd_lines() {
+        let receiver = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_read_timeout(Some(std::time::Duration::from_secs(2))).unwrap();
+        let mut broker = Broker::new();
+        broker.set_metric_sink(Arc::new(StatsdSink::new(receiver.local_addr().unwrap(), "mqtt").unwrap()));
+        dispatch_with(&mut broker, MqttPacketType::Connect, &connect_packet("test", 0x02, &[])).unwrap();
+        dispatch_as(&mut broker, "test", MqttPacketType::Publish, &[0x30, 0x05, 0x00, 0x01, 0x61, 0x68, 0x69]).unwrap();
+
+        let mut buffer = [0; 128];
+        let lines: Vec<String> = (0..4)
+            .map(|_| {
+                let length = receiver.recv(&mut buffer).unwrap();
+                String::from_utf8(buffer[..length].to_vec()).unwrap()
+            })
+            .collect();
+        assert_eq!(lines, vec!["mqtt.clients.connects:1|c", "mqtt.clients.connected:1|g", "mqtt.messages.published:1|c", "mqtt.messages.fan_out:0|h"]);
+    }
+
+    #[test]
+    fn test_retained_will_can_be_cleared_by_a_later_publish() {
+        let mut broker = Broker::new();
+        // clean session with a retained QoS 0 will "bye" on topic "a"
+        dispatch_with(&mut broker, MqttPacketType::Connect, &connect_packet("test", 0x26, &[length_prefixed("a"), length_prefixed("bye")].concat())).unwrap();
+        broker.connection_lost("test");
+        assert_eq!(broker.retained_message("a"), Some(&b"bye"[..]));
+
+        // a retained PUBLISH with an empty payload to the will topic
+        publish(&mut broker, &[0x31, 0x03, 0x00, 0x01, 0x61]).unwrap();
+        assert_eq!(broker.retained_message("a"), None);
     }
 }
@@ -0,0 +1,102 @@
+use crate::models::error::MqttError;
+use crate::models::mqtt_headers::MqttHeaders;
+
+// Collects the bytes a client sends until they form complete MQTT packets. A WebSocket frame may
+// carry several packets or only part of one, so packets are cut out using the remaining length of
+// their fixed header and whatever is left over waits for the next frame.
+#[derive(Debug)]
+pub struct PacketBuffer {
+    buffer: Vec<u8>,
+    max_packet_size: usize,
+}
+
+impl PacketBuffer {
+    pub fn new(max_packet_size: usize) -> Self {
+        PacketBuffer { buffer: Vec::new(), max_packet_size }
+    }
+
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    // Takes the next complete packet out of the buffer, or returns `None` until enough bytes arrived.
+    // That includes a remaining length field that is itself split across frames.
+    pub fn next_packet(&mut self) -> Result<Option<Vec<u8>>, MqttError> {
+        if self.buffer.is_empty() {
+            return Ok(None);
+        }
+        let fixed_header = match MqttHeaders::parse(&self.buffer) {
+            Ok(fixed_header) => fixed_header,
+            Err(MqttError::Truncated(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let packet_size = fixed_header.packet_size();
+        if packet_size > self.max_packet_size {
+            return Err(MqttError::PacketTooLarge(packet_size));
+        }
+        if self.buffer.len() < packet_size {
+            return Ok(None);
+        }
+        Ok(Some(self.buffer.drain(..packet_size).collect()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod packet_buffer_tests {
+    use super::*;
+
+    const MAX_PACKET_SIZE: usize = 1024;
+    const PINGREQ: [u8; 2] = [0xC0, 0x00];
+    // PUBLISH to "a" with the payload "hi"
+    const PUBLISH: [u8; 7] = [0x30, 0x05, 0x00, 0x01, 0x61, 0x68, 0x69];
+
+    #[test]
+    fn test_two_packets_in_one_frame() {
+        let mut buffer = PacketBuffer::new(MAX_PACKET_SIZE);
+        buffer.extend(&[&PUBLISH[..], &PINGREQ[..]].concat());
+        assert_eq!(buffer.next_packet(), Ok(Some(PUBLISH.to_vec())));
+        assert_eq!(buffer.next_packet(), Ok(Some(PINGREQ.to_vec())));
+        assert_eq!(buffer.next_packet(), Ok(None));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_packet_split_across_frames() {
+        let mut buffer = PacketBuffer::new(MAX_PACKET_SIZE);
+        buffer.extend(&PUBLISH[..4]);
+        assert_eq!(buffer.next_packet(), Ok(None));
+        buffer.extend(&PUBLISH[4..]);
+        assert_eq!(buffer.next_packet(), Ok(Some(PUBLISH.to_vec())));
+    }
+
+    #[test]
+    fn test_remaining_length_split_across_frames() {
+        // PUBLISH with a remaining length of 200, which takes two bytes to encode
+        let mut publish = vec![0x30, 0xC8, 0x01, 0x00, 0x01, 0x61];
+        publish.resize(3 + 200, 0x78);
+        let mut buffer = PacketBuffer::new(MAX_PACKET_SIZE);
+        buffer.extend(&publish[..2]);
+        assert_eq!(buffer.next_packet(), Ok(None));
+        buffer.extend(&publish[2..]);
+        assert_eq!(buffer.next_packet(), Ok(Some(publish)));
+    }
+
+    #[test]
+    fn test_oversized_packet_is_rejected_from_its_header() {
+        let mut buffer = PacketBuffer::new(MAX_PACKET_SIZE);
+        // PUBLISH announcing the largest remaining length there is
+        buffer.extend(&[0x30, 0xFF, 0xFF, 0xFF, 0x7F]);
+        assert_eq!(buffer.next_packet(), Err(MqttError::PacketTooLarge(268_435_460)));
+    }
+
+    #[test]
+    fn test_invalid_packet_type() {
+        let mut buffer = PacketBuffer::new(MAX_PACKET_SIZE);
+        buffer.extend(&[0xF0, 0x00]);
+        assert!(buffer.next_packet().is_err());
+    }
+}
@@ -1,6 +1,8 @@
+use crate::models::error::MqttError;
 use crate::models::mqtt_headers::MqttHeaders;
-use crate::models::mqtt_payloads::{Payload, PayloadFactory};
+use crate::models::mqtt_payloads::{Default, Payload, PayloadFactory};
 use crate::models::mqtt_headers::ConnAckHeader;
+use crate::models::mqtt_types::{ConnAckReturnCode, MqttPacketType};
 
 pub struct ConnAck {
     pub fixed_header: MqttHeaders,
@@ -17,12 +19,28 @@ impl ConnAck {
         }
     }
 
-    pub fn from_bytes(data: Vec<u8>) -> Self {
-        let fixed_header = MqttHeaders::parse(&data);
-        let fixed_header_size = fixed_header.unwrap().incomming_byte_size();
-        let variable_header = ConnAckHeader::from_bytes(&data[fixed_header_size..ConnAckHeader::incomming_byte_size() + fixed_header_size]);
-        let payload = PayloadFactory::parse_payload(&variable_header, data[0..0].to_vec());
-        ConnAck::new(fixed_header.unwrap(), variable_header, payload)
+    pub fn new_success(session_present: bool) -> Self {
+        let fixed_header = MqttHeaders::new(MqttPacketType::ConnAck, 0b0000, 2);
+        let variable_header = ConnAckHeader::new(session_present, ConnAckReturnCode::Accepted.to_u8());
+        ConnAck::new(fixed_header, variable_header, Payload::Default(Default))
+    }
+
+    // A rejected CONNACK never reports a session [MQTT-3.2.2-4]
+    pub fn new_rejected(return_code: ConnAckReturnCode) -> Self {
+        let fixed_header = MqttHeaders::new(MqttPacketType::ConnAck, 0b0000, 2);
+        let variable_header = ConnAckHeader::new(false, return_code.to_u8());
+        ConnAck::new(fixed_header, variable_header, Payload::Default(Default))
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, MqttError> {
+        let fixed_header = MqttHeaders::parse(&data)?;
+        if fixed_header.packet_type != MqttPacketType::ConnAck {
+            return Err(MqttError::UnexpectedPacketType(fixed_header.packet_type));
+        }
+        let fixed_header_size = fixed_header.incomming_byte_size();
+        let variable_header = ConnAckHeader::from_bytes(&data[fixed_header_size..])?;
+        let payload = PayloadFactory::parse_payload(&variable_header, data[0..0].to_vec())?;
+        Ok(ConnAck::new(fixed_header, variable_header, payload))
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
@@ -34,3 +52,42 @@ impl ConnAck {
         buffer
     }
 }
+
+#[cfg(test)]
+mod connack_tests {
+    use super::*;
+
+    #[test]
+    fn test_connack_truncated() {
+        assert_eq!(ConnAck::from_bytes(vec![0x20]).err(), Some(MqttError::Truncated("Fixed Header")));
+        assert_eq!(ConnAck::from_bytes(vec![0x20, 0x02, 0x01]).err(), Some(MqttError::Truncated("CONNACK Variable Header")));
+        assert_eq!(ConnAck::from_bytes(vec![0x30, 0x00]).err(), Some(MqttError::UnexpectedPacketType(MqttPacketType::Publish)));
+    }
+
+    #[test]
+    fn test_connack_from_bytes() {
+        let connack = ConnAck::from_bytes(vec![0x20, 0x02, 0x01, 0x00]).unwrap();
+        assert_eq!(connack.fixed_header.packet_type, MqttPacketType::ConnAck);
+        assert!(connack.variable_header.session_present);
+        assert_eq!(connack.variable_header.return_code, 0);
+    }
+
+    #[test]
+    fn test_connack_new_success() {
+        let connack = ConnAck::new_success(false);
+        assert_eq!(ConnAckReturnCode::from_u8(connack.variable_header.return_code), Ok(ConnAckReturnCode::Accepted));
+        assert_eq!(connack.to_bytes(), vec![0x20, 0x02, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_connack_new_rejected() {
+        let connack = ConnAck::new_rejected(ConnAckReturnCode::BadCredentials);
+        assert!(!connack.variable_header.session_present);
+        assert_eq!(connack.to_bytes(), vec![0x20, 0x02, 0x00, 0x04]);
+    }
+
+    #[test]
+    fn test_connack_from_bytes_wrong_packet_type() {
+        assert!(ConnAck::from_bytes(vec![0x30, 0x02, 0x01, 0x00]).is_err());
+    }
+}
@@ -1,7 +1,8 @@
-use log::{info, warn, error};
+use log::{info, error};
 
+use crate::models::error::MqttError;
 use crate::models::mqtt_headers::{MqttHeaders, ConnectHeader};
-use crate::models::mqtt_payloads::{Payload, ConnectPayload};
+use crate::models::mqtt_payloads::Payload;
 use crate::models::mqtt_payloads::PayloadFactory;
 use crate::models::mqtt_types::MqttPacketType;
 
@@ -12,7 +13,8 @@ pub struct Connect {
 }
 
 impl Connect {
-    const MINIMUM_REMAINING_LENGTH: u32 = 7;
+    // variable header (10 bytes) plus the length prefix of the client id
+    const MINIMUM_REMAINING_LENGTH: u32 = 12;
 
     pub fn new(fixed_header: MqttHeaders, variable_header: ConnectHeader, payload: Payload) -> Self {
         Connect {
@@ -22,21 +24,87 @@ impl Connect {
         }
     }
 
-    pub fn from_bytes(data: Vec<u8>) -> Self {
-        let fixed_header = MqttHeaders::parse(&data);
-        if fixed_header.unwrap().remaining_length <= Self::MINIMUM_REMAINING_LENGTH {
-           error!("The CONNECT packets remeining length is to short!");
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, MqttError> {
+        let fixed_header = MqttHeaders::parse(&data)?;
+        if fixed_header.packet_type != MqttPacketType::Connect {
+            return Err(MqttError::UnexpectedPacketType(fixed_header.packet_type));
         }
-        let variable_header = ConnectHeader::from_bytes(&data[2..10]);
+        if fixed_header.remaining_length < Self::MINIMUM_REMAINING_LENGTH {
+            error!("The CONNECT packets remeining length is to short!");
+            return Err(MqttError::MalformedPacket("CONNECT remaining length is too short"));
+        }
+        let variable_header_start = fixed_header.incomming_byte_size();
+        if data.len() < variable_header_start + Self::MINIMUM_REMAINING_LENGTH as usize {
+            error!("The CONNECT packet is shorter than its remaining length!");
+            return Err(MqttError::Truncated("CONNECT packet"));
+        }
+        let payload_start = variable_header_start + ConnectHeader::size();
+        let variable_header = ConnectHeader::from_bytes(&data[variable_header_start..payload_start])?;
         info!("{:?}", fixed_header);
         info!("{:?}", variable_header);
-        let payload = PayloadFactory::parse_payload(&variable_header, data[10..].to_vec());
+        let payload = PayloadFactory::parse_payload(&variable_header, data[payload_start..].to_vec())?;
         info!("{:?}", payload);
         //let connect_payload = match payload {
         //    Payload::Connect(connect_payload) => connect_payload, // Extract ConnectPayload
         //    _ => panic!("Expected ConnectPayload, found {:?}", payload), // Handle other cases
         //};
-        Connect::new(fixed_header.unwrap(), variable_header, payload)
+        Ok(Connect::new(fixed_header, variable_header, payload))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Will {
+    pub topic: String,
+    pub message: String,
+    pub qos: u8,
+    pub retain: bool,
+}
+
+// High level view of a CONNECT packet so the handler does not have to reach into
+// the variable header flags and the payload separately
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectRequest {
+    pub client_id: String,
+    pub protocol_level: u8,
+    pub clean_session: bool,
+    pub keep_alive: u16,
+    pub will: Option<Will>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl ConnectRequest {
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, MqttError> {
+        Self::from_connect(Connect::from_bytes(data)?)
+    }
+
+    pub fn from_connect(connect: Connect) -> Result<Self, MqttError> {
+        let header = connect.variable_header;
+        let payload = match connect.payload {
+            Payload::Connect(connect_payload) => connect_payload,
+            _ => return Err(MqttError::MalformedPacket("Invalid payload type")),
+        };
+
+        let will = if header.will_flag() {
+            Some(Will {
+                topic: payload.will_topic.unwrap_or_default(),
+                message: payload.will_message.unwrap_or_default(),
+                qos: header.will_qos(),
+                retain: header.will_retain(),
+            })
+        } else {
+            None
+        };
+
+        Ok(ConnectRequest {
+            client_id: payload.client_id.unwrap_or_default(),
+            protocol_level: header.protocol_level,
+            clean_session: header.clean_session(),
+            keep_alive: header.keep_alive,
+            will,
+            username: payload.username.filter(|_| header.user_name_flag()),
+            password: payload.password.filter(|_| header.password_flag()),
+        })
     }
 }
 
@@ -47,8 +115,8 @@ mod connect_tests {
     #[test]
     fn test_connect_from_bytes() {
         //let data = vec![0x10, 0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, 0x04, 0x02, 0x00, 0x3C, 0x00, 0x0A, 0x74, 0x65, 0x73, 0x74, 0x75, 0x73, 0x65, 0x72, 0x6E, 0x61, 0x6D, 0x65, 0x00, 0x0A, 0x74, 0x65, 0x73, 0x74, 0x75, 0x73, 0x65, 0x72, 0x70, 0x77, 0x64];
-        let header_data = vec![0x10, 0x26];
-        let connect_variable_header_data = vec![0x4D, 0x51, 0x54, 0x54, 0x04, 0xC4, 0x00, 0x3C];
+        let header_data = [0x10, 0x28];
+        let connect_variable_header_data = [0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, 0x04, 0xC4, 0x00, 0x3C];
         let connect_payload_data: Vec<u8> = vec![
             0x00, 0x04, 0x74, 0x65, 0x73, 0x74, // Client ID: test
             0x00, 0x04, 0x74, 0x65, 0x73, 0x74, // Will Topic: test
@@ -58,7 +126,7 @@ mod connect_tests {
         ]; 
 
         let data = [&header_data[..], &connect_variable_header_data[..], &connect_payload_data[..]].concat();
-        let connect = Connect::from_bytes(data);
+        let connect = Connect::from_bytes(data).unwrap();
         assert_eq!(connect.fixed_header.packet_type, MqttPacketType::Connect);
         //assert_eq!(connect.fixed_header.flags, 0);
         //assert_eq!(connect.fixed_header.remaining_length, 0);
@@ -78,4 +146,69 @@ mod connect_tests {
         assert_eq!(connect_payload.username.unwrap(), "test");
         assert_eq!(connect_payload.password.unwrap(), "test");
     }
+    #[test]
+    fn test_connect_from_bytes_wrong_packet_type() {
+        let header_data = [0x30, 0x10];
+        let connect_variable_header_data = [0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, 0x04, 0x00, 0x00, 0x0A];
+        let connect_payload_data = [0x00, 0x04, 0x74, 0x65, 0x73, 0x74];
+
+        let data = [&header_data[..], &connect_variable_header_data[..], &connect_payload_data[..]].concat();
+        assert!(Connect::from_bytes(data).is_err());
+    }
+
+    #[test]
+    fn test_connect_from_bytes_zero_remaining_length() {
+        assert!(Connect::from_bytes(vec![0x10, 0x00]).is_err());
+    }
+
+    #[test]
+    fn test_connect_from_bytes_truncated() {
+        // remaining length claims a full CONNECT but only part of the variable header follows
+        assert!(Connect::from_bytes(vec![0x10, 0x10, 0x00, 0x04, 0x4D, 0x51]).is_err());
+    }
+
+    #[test]
+    fn test_connect_request_from_bytes() {
+        let header_data = [0x10, 0x28];
+        // will QoS 1, will retain, clean session, user name and password
+        let connect_variable_header_data = [0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, 0x04, 0xEE, 0x00, 0x3C];
+        let connect_payload_data: Vec<u8> = vec![
+            0x00, 0x04, 0x74, 0x65, 0x73, 0x74, // Client ID: test
+            0x00, 0x04, 0x77, 0x69, 0x6C, 0x6C, // Will Topic: will
+            0x00, 0x04, 0x62, 0x79, 0x65, 0x21, // Will Message: bye!
+            0x00, 0x04, 0x75, 0x73, 0x65, 0x72, // User Name: user
+            0x00, 0x04, 0x70, 0x61, 0x73, 0x73, // Password: pass
+        ];
+
+        let data = [&header_data[..], &connect_variable_header_data[..], &connect_payload_data[..]].concat();
+        let request = ConnectRequest::from_bytes(data).unwrap();
+        assert_eq!(request.client_id, "test");
+        assert_eq!(request.protocol_level, 4);
+        assert!(request.clean_session);
+        assert_eq!(request.keep_alive, 60);
+        assert_eq!(request.will, Some(Will {
+            topic: "will".to_string(),
+            message: "bye!".to_string(),
+            qos: 1,
+            retain: true,
+        }));
+        assert_eq!(request.username.as_deref(), Some("user"));
+        assert_eq!(request.password.as_deref(), Some("pass"));
+    }
+
+    #[test]
+    fn test_connect_request_without_optional_fields() {
+        let header_data = [0x10, 0x10];
+        let connect_variable_header_data = [0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, 0x04, 0x00, 0x00, 0x0A];
+        let connect_payload_data = [0x00, 0x04, 0x74, 0x65, 0x73, 0x74]; // Client ID: test
+
+        let data = [&header_data[..], &connect_variable_header_data[..], &connect_payload_data[..]].concat();
+        let request = ConnectRequest::from_bytes(data).unwrap();
+        assert_eq!(request.client_id, "test");
+        assert!(!request.clean_session);
+        assert_eq!(request.keep_alive, 10);
+        assert_eq!(request.will, None);
+        assert_eq!(request.username, None);
+        assert_eq!(request.password, None);
+    }
 }
@@ -1,2 +1,9 @@
 pub mod connect;
 pub mod connack;
+pub mod publish;
+pub mod subscribe;
+pub mod suback;
+pub mod puback;
+pub mod pubrec;
+pub mod pubrel;
+pub mod pubcomp;
@@ -0,0 +1,59 @@
+use crate::models::error::MqttError;
+use crate::models::mqtt_headers::{MqttHeaders, PacketIdHeader};
+use crate::models::mqtt_payloads::{Default, Payload};
+use crate::models::mqtt_types::MqttPacketType;
+
+pub struct PubComp {
+    pub fixed_header: MqttHeaders,
+    pub variable_header: PacketIdHeader,
+    pub payload: Payload,
+}
+
+impl PubComp {
+    pub fn new(fixed_header: MqttHeaders, variable_header: PacketIdHeader, payload: Payload) -> Self {
+        PubComp {
+            fixed_header,
+            variable_header,
+            payload,
+        }
+    }
+
+    // Completes the QoS 2 handshake once the PUBREL for the packet identifier arrived [MQTT-4.3.3-2]
+    pub fn new_for(packet_id: u16) -> Self {
+        let fixed_header = MqttHeaders::new(MqttPacketType::PubComp, 0b0000, PacketIdHeader::size() as u32);
+        PubComp::new(fixed_header, PacketIdHeader::new(packet_id), Payload::Default(Default))
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, MqttError> {
+        let fixed_header = MqttHeaders::parse(&data)?;
+        if fixed_header.packet_type != MqttPacketType::PubComp {
+            return Err(MqttError::UnexpectedPacketType(fixed_header.packet_type));
+        }
+        let variable_header = PacketIdHeader::from_bytes(&data[fixed_header.incomming_byte_size()..])?;
+        Ok(PubComp::new(fixed_header, variable_header, Payload::Default(Default)))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.extend(self.fixed_header.to_bytes());
+        buffer.extend(self.variable_header.to_bytes());
+        buffer
+    }
+}
+
+#[cfg(test)]
+mod pubcomp_tests {
+    use super::*;
+
+    #[test]
+    fn test_pubcomp_to_bytes() {
+        assert_eq!(PubComp::new_for(0x000A).to_bytes(), vec![0x70, 0x02, 0x00, 0x0A]);
+    }
+
+    #[test]
+    fn test_pubcomp_from_bytes() {
+        let pubcomp = PubComp::from_bytes(vec![0x70, 0x02, 0x12, 0x34]).unwrap();
+        assert_eq!(pubcomp.variable_header.packet_id, 0x1234);
+        assert!(PubComp::from_bytes(vec![0x62, 0x02, 0x12, 0x34]).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+use crate::models::error::MqttError;
+use crate::models::mqtt_headers::{MqttHeaders, PublishHeader};
+use crate::models::mqtt_payloads::{Payload, PublishPayload};
+use crate::models::mqtt_payloads::PayloadFactory;
+use crate::models::mqtt_types::MqttPacketType;
+
+#[derive(Debug)]
+pub struct Publish {
+    pub fixed_header: MqttHeaders,
+    pub variable_header: PublishHeader,
+    pub payload: Payload,
+}
+
+impl Publish {
+    const DUP_FLAG: u8 = 0b00001000;
+    const QOS_MASK: u8 = 0b00000110;
+    const RETAIN_FLAG: u8 = 0b00000001;
+
+    pub fn new(fixed_header: MqttHeaders, variable_header: PublishHeader, payload: Payload) -> Self {
+        Publish {
+            fixed_header,
+            variable_header,
+            payload,
+        }
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, MqttError> {
+        let fixed_header = MqttHeaders::parse(&data)?;
+        if fixed_header.packet_type != MqttPacketType::Publish {
+            return Err(MqttError::UnexpectedPacketType(fixed_header.packet_type));
+        }
+        let variable_header_start = fixed_header.incomming_byte_size();
+        let packet_end = variable_header_start + fixed_header.remaining_length as usize;
+        if data.len() < packet_end {
+            return Err(MqttError::Truncated("PUBLISH packet"));
+        }
+        let qos = (fixed_header.flags & Self::QOS_MASK) >> 1;
+        // Both QoS bits set is not a QoS level [MQTT-3.3.1-4]
+        if qos == 3 {
+            return Err(MqttError::MalformedPacket("PUBLISH with QoS 3"));
+        }
+        let variable_header = PublishHeader::from_bytes(&data[variable_header_start..packet_end], qos)?;
+        let payload_start = variable_header_start + variable_header.size(qos);
+        let payload = PayloadFactory::parse_payload(&variable_header, data[payload_start..packet_end].to_vec())?;
+        Ok(Publish::new(fixed_header, variable_header, payload))
+    }
+
+    pub fn dup(&self) -> bool {
+        self.fixed_header.flags & Self::DUP_FLAG != 0
+    }
+
+    pub fn qos(&self) -> u8 {
+        (self.fixed_header.flags & Self::QOS_MASK) >> 1
+    }
+
+    pub fn retain(&self) -> bool {
+        self.fixed_header.flags & Self::RETAIN_FLAG != 0
+    }
+
+    // The QoS 0 copy of a message that is forwarded to the subscribers of its topic
+    pub fn for_delivery(topic_name: &str, payload: &[u8]) -> Self {
+        let remaining_length = (2 + topic_name.len() + payload.len()) as u32;
+        Publish::new(
+            MqttHeaders::new(MqttPacketType::Publish, 0, remaining_length),
+            PublishHeader {
+                topic_name: topic_name.to_string(),
+                packet_id: 0,
+            },
+            Payload::Publish(PublishPayload {
+                payload: payload.to_vec(),
+            }),
+        )
+    }
+
+    // The copy of a retained message sent to a new subscription, with the RETAIN flag set [MQTT-3.3.1-8]
+    pub fn for_retained_delivery(topic_name: &str, payload: &[u8]) -> Self {
+        let mut publish = Self::for_delivery(topic_name, payload);
+        publish.fixed_header.flags |= Self::RETAIN_FLAG;
+        publish
+    }
+
+    pub fn payload_bytes(&self) -> &[u8] {
+        match &self.payload {
+            Payload::Publish(publish_payload) => &publish_payload.payload,
+            _ => &[],
+        }
+    }
+
+    // The remaining length is taken from what is actually written, so a stale value in the fixed header
+    // cannot produce a malformed packet
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut body = self.variable_header.to_bytes(self.qos());
+        if let Payload::Publish(publish_payload) = &self.payload {
+            body.extend(publish_payload.to_bytes());
+        }
+        let fixed_header = MqttHeaders::new(self.fixed_header.packet_type, self.fixed_header.flags, body.len() as u32);
+        let mut buffer = fixed_header.to_bytes();
+        buffer.extend(body);
+        buffer
+    }
+}
+
+#[cfg(test)]
+mod publish_tests {
+    use super::*;
+
+    #[test]
+    fn test_publish_from_bytes() {
+        // PUBLISH QoS 1 to topic "a/b" with packet id 10 and payload "hi"
+        let data = vec![0x32, 0x09, 0x00, 0x03, 0x61, 0x2F, 0x62, 0x00, 0x0A, 0x68, 0x69];
+        let publish = Publish::from_bytes(data).unwrap();
+        assert_eq!(publish.variable_header.topic_name, "a/b");
+        assert_eq!(publish.variable_header.packet_id, 10);
+        match publish.payload {
+            Payload::Publish(payload) => assert_eq!(payload.payload, b"hi"),
+            other => panic!("Expected PublishPayload, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_flag_accessors() {
+        let publish = Publish::new(
+            MqttHeaders::new(MqttPacketType::Publish, 0b1011, 0),
+            PublishHeader {
+                topic_name: "a".to_string(),
+                packet_id: 1,
+            },
+            Payload::Publish(PublishPayload { payload: Vec::new() }),
+        );
+        assert!(publish.dup());
+        assert_eq!(publish.qos(), 1);
+        assert!(publish.retain());
+
+        let publish = Publish::for_delivery("a", b"");
+        assert!(!publish.dup());
+        assert_eq!(publish.qos(), 0);
+        assert!(!publish.retain());
+    }
+
+    #[test]
+    fn test_for_delivery_to_bytes() {
+        let publish = Publish::for_delivery("a/b", b"hi");
+        assert_eq!(publish.to_bytes(), vec![0x30, 0x07, 0x00, 0x03, 0x61, 0x2F, 0x62, 0x68, 0x69]);
+    }
+
+    #[test]
+    fn test_for_retained_delivery_to_bytes() {
+        let publish = Publish::for_retained_delivery("a/b", b"hi");
+        assert!(publish.retain());
+        assert_eq!(publish.to_bytes(), vec![0x31, 0x07, 0x00, 0x03, 0x61, 0x2F, 0x62, 0x68, 0x69]);
+    }
+
+    #[test]
+    fn test_to_bytes_recomputes_remaining_length() {
+        let mut publish = Publish::from_bytes(vec![0x32, 0x09, 0x00, 0x03, 0x61, 0x2F, 0x62, 0x00, 0x0A, 0x68, 0x69]).unwrap();
+        publish.fixed_header.remaining_length = 0;
+        assert_eq!(publish.to_bytes(), vec![0x32, 0x09, 0x00, 0x03, 0x61, 0x2F, 0x62, 0x00, 0x0A, 0x68, 0x69]);
+    }
+
+    #[test]
+    fn test_publish_truncated() {
+        let data = vec![0x30, 0x09, 0x00, 0x03, 0x61, 0x2F, 0x62];
+        assert_eq!(Publish::from_bytes(data).unwrap_err(), MqttError::Truncated("PUBLISH packet"));
+    }
+
+    #[test]
+    fn test_publish_with_qos_3_is_malformed() {
+        let data = vec![0x36, 0x09, 0x00, 0x03, 0x61, 0x2F, 0x62, 0x00, 0x0A, 0x68, 0x69];
+        assert_eq!(Publish::from_bytes(data).unwrap_err(), MqttError::MalformedPacket("PUBLISH with QoS 3"));
+    }
+}
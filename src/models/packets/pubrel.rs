@@ -0,0 +1,74 @@
+use crate::models::error::MqttError;
+use crate::models::mqtt_headers::{MqttHeaders, PacketIdHeader};
+use crate::models::mqtt_payloads::{Default, Payload};
+use crate::models::mqtt_types::MqttPacketType;
+
+pub struct PubRel {
+    pub fixed_header: MqttHeaders,
+    pub variable_header: PacketIdHeader,
+    pub payload: Payload,
+}
+
+impl PubRel {
+    // Bits 3,2,1 and 0 of the fixed header of the PUBREL packet are reserved and MUST be set to 0,0,1 and 0 [MQTT-3.6.1-1]
+    const FIXED_HEADER_FLAGS: u8 = 0b0010;
+
+    pub fn new(fixed_header: MqttHeaders, variable_header: PacketIdHeader, payload: Payload) -> Self {
+        PubRel {
+            fixed_header,
+            variable_header,
+            payload,
+        }
+    }
+
+    pub fn new_for(packet_id: u16) -> Self {
+        let fixed_header = MqttHeaders::new(MqttPacketType::PubRel, Self::FIXED_HEADER_FLAGS, PacketIdHeader::size() as u32);
+        PubRel::new(fixed_header, PacketIdHeader::new(packet_id), Payload::Default(Default))
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, MqttError> {
+        let fixed_header = MqttHeaders::parse(&data)?;
+        if fixed_header.packet_type != MqttPacketType::PubRel {
+            return Err(MqttError::UnexpectedPacketType(fixed_header.packet_type));
+        }
+        if fixed_header.flags != Self::FIXED_HEADER_FLAGS {
+            return Err(MqttError::MalformedPacket("Reserved flags of the PUBREL fixed header are not 0010"));
+        }
+        let variable_header = PacketIdHeader::from_bytes(&data[fixed_header.incomming_byte_size()..])?;
+        Ok(PubRel::new(fixed_header, variable_header, Payload::Default(Default)))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.extend(self.fixed_header.to_bytes());
+        buffer.extend(self.variable_header.to_bytes());
+        buffer
+    }
+}
+
+#[cfg(test)]
+mod pubrel_tests {
+    use super::*;
+
+    #[test]
+    fn test_pubrel_to_bytes() {
+        assert_eq!(PubRel::new_for(0x000A).to_bytes(), vec![0x62, 0x02, 0x00, 0x0A]);
+    }
+
+    #[test]
+    fn test_pubrel_from_bytes() {
+        let pubrel = PubRel::from_bytes(vec![0x62, 0x02, 0x12, 0x34]).unwrap();
+        assert_eq!(pubrel.variable_header.packet_id, 0x1234);
+        assert!(PubRel::from_bytes(vec![0x50, 0x02, 0x12, 0x34]).is_err());
+    }
+
+    #[test]
+    fn test_pubrel_with_wrong_fixed_header_flags() {
+        for first_byte in [0x60, 0x63, 0x6A] {
+            assert_eq!(
+                PubRel::from_bytes(vec![first_byte, 0x02, 0x12, 0x34]).err(),
+                Some(MqttError::MalformedPacket("Reserved flags of the PUBREL fixed header are not 0010"))
+            );
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use crate::models::error::MqttError;
+use crate::models::mqtt_headers::{MqttHeaders, SubAckHeader};
+use crate::models::mqtt_payloads::{Payload, PayloadFactory, SubAckPayload};
+use crate::models::mqtt_types::MqttPacketType;
+
+pub struct SubAck {
+    pub fixed_header: MqttHeaders,
+    pub variable_header: SubAckHeader,
+    pub payload: Payload,
+}
+
+impl SubAck {
+    // Return code for a topic filter the server did not accept [MQTT-3.9.3]
+    pub const FAILURE_RETURN_CODE: u8 = 0x80;
+
+    pub fn new(fixed_header: MqttHeaders, variable_header: SubAckHeader, payload: Payload) -> Self {
+        SubAck {
+            fixed_header,
+            variable_header,
+            payload,
+        }
+    }
+
+    // The packet identifier MUST be the same as in the SUBSCRIBE being acknowledged [MQTT-3.8.4-2]
+    pub fn new_granted(packet_id: u16, return_codes: Vec<u8>) -> Self {
+        let remaining_length = (SubAckHeader::size() + return_codes.len()) as u32;
+        let fixed_header = MqttHeaders::new(MqttPacketType::SubAck, 0b0000, remaining_length);
+        SubAck::new(fixed_header, SubAckHeader::new(packet_id), Payload::SubAck(SubAckPayload { return_codes }))
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, MqttError> {
+        let fixed_header = MqttHeaders::parse(&data)?;
+        if fixed_header.packet_type != MqttPacketType::SubAck {
+            return Err(MqttError::UnexpectedPacketType(fixed_header.packet_type));
+        }
+        let variable_header_start = fixed_header.incomming_byte_size();
+        let packet_end = variable_header_start + fixed_header.remaining_length as usize;
+        if data.len() < packet_end {
+            return Err(MqttError::Truncated("SUBACK packet"));
+        }
+        let variable_header = SubAckHeader::from_bytes(&data[variable_header_start..packet_end])?;
+        let payload_start = variable_header_start + SubAckHeader::size();
+        let payload = PayloadFactory::parse_payload(&variable_header, data[payload_start..packet_end].to_vec())?;
+        Ok(SubAck::new(fixed_header, variable_header, payload))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.extend(self.fixed_header.to_bytes());
+        buffer.extend(self.variable_header.to_bytes());
+        if let Payload::SubAck(suback_payload) = &self.payload {
+            buffer.extend(suback_payload.to_bytes());
+        }
+        buffer
+    }
+}
+
+#[cfg(test)]
+mod suback_tests {
+    use super::*;
+
+    #[test]
+    fn test_suback_to_bytes() {
+        let suback = SubAck::new_granted(10, vec![0x00, 0x01, SubAck::FAILURE_RETURN_CODE]);
+        assert_eq!(suback.to_bytes(), vec![0x90, 0x05, 0x00, 0x0A, 0x00, 0x01, 0x80]);
+    }
+
+    #[test]
+    fn test_suback_from_bytes() {
+        let suback = SubAck::from_bytes(vec![0x90, 0x04, 0x12, 0x34, 0x01, 0x00]).unwrap();
+        assert_eq!(suback.variable_header.packet_id, 0x1234);
+        match suback.payload {
+            Payload::SubAck(suback_payload) => assert_eq!(suback_payload.return_codes, vec![0x01, 0x00]),
+            _ => panic!("Expected SubAckPayload"),
+        }
+    }
+}
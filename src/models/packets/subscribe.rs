@@ -0,0 +1,107 @@
+use crate::models::error::MqttError;
+use crate::models::mqtt_headers::{MqttHeaders, SubscribeHeader};
+use crate::models::mqtt_payloads::Payload;
+use crate::models::mqtt_payloads::PayloadFactory;
+use crate::models::mqtt_types::MqttPacketType;
+
+#[derive(Debug)]
+pub struct Subscribe {
+    pub fixed_header: MqttHeaders,
+    pub variable_header: SubscribeHeader,
+    pub payload: Payload,
+}
+
+impl Subscribe {
+    // Bits 3,2,1 and 0 of the fixed header of the SUBSCRIBE packet are reserved and MUST be set to 0,0,1 and 0 [MQTT-3.8.1-1]
+    const FIXED_HEADER_FLAGS: u8 = 0b0010;
+
+    pub fn new(fixed_header: MqttHeaders, variable_header: SubscribeHeader, payload: Payload) -> Self {
+        Subscribe {
+            fixed_header,
+            variable_header,
+            payload,
+        }
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, MqttError> {
+        let fixed_header = MqttHeaders::parse(&data)?;
+        if fixed_header.packet_type != MqttPacketType::Subscribe {
+            return Err(MqttError::UnexpectedPacketType(fixed_header.packet_type));
+        }
+        if fixed_header.flags != Self::FIXED_HEADER_FLAGS {
+            return Err(MqttError::MalformedPacket("Reserved flags of the SUBSCRIBE fixed header are not 0010"));
+        }
+        let variable_header_start = fixed_header.incomming_byte_size();
+        let packet_end = variable_header_start + fixed_header.remaining_length as usize;
+        if data.len() < packet_end {
+            return Err(MqttError::Truncated("SUBSCRIBE packet"));
+        }
+        let variable_header = SubscribeHeader::from_bytes(&data[variable_header_start..packet_end])?;
+        let payload_start = variable_header_start + SubscribeHeader::size();
+        // The payload of a SUBSCRIBE packet MUST contain at least one Topic Filter / QoS pair [MQTT-3.8.3-3]
+        if payload_start >= packet_end {
+            return Err(MqttError::MalformedPacket("SUBSCRIBE packet has no topic filters"));
+        }
+        let payload = PayloadFactory::parse_payload(&variable_header, data[payload_start..packet_end].to_vec())?;
+        Ok(Subscribe::new(fixed_header, variable_header, payload))
+    }
+
+    // The requested topic filters with their QoS, in the order they appear in the packet
+    pub fn topic_filters(&self) -> Vec<(String, u8)> {
+        match &self.payload {
+            Payload::Subscribe(subscribe_payload) => subscribe_payload.topic_filters.clone(),
+            _ => Vec::new(),
+        }
+    }
+
+    // The remaining length is taken from what is actually written, as for `Publish::to_bytes`
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut body = self.variable_header.to_bytes();
+        if let Payload::Subscribe(subscribe_payload) = &self.payload {
+            body.extend(subscribe_payload.to_bytes());
+        }
+        let fixed_header = MqttHeaders::new(self.fixed_header.packet_type, self.fixed_header.flags, body.len() as u32);
+        let mut buffer = fixed_header.to_bytes();
+        buffer.extend(body);
+        buffer
+    }
+}
+
+#[cfg(test)]
+mod subscribe_tests {
+    use super::*;
+
+    #[test]
+    fn test_subscribe_from_bytes() {
+        // SUBSCRIBE packet id 10 to "a/b" with QoS 1
+        let data = vec![0x82, 0x08, 0x00, 0x0A, 0x00, 0x03, 0x61, 0x2F, 0x62, 0x01];
+        let subscribe = Subscribe::from_bytes(data).unwrap();
+        assert_eq!(subscribe.variable_header.packet_id, 10);
+        assert_eq!(subscribe.topic_filters(), vec![("a/b".to_string(), 1)]);
+    }
+
+    #[test]
+    fn test_subscribe_to_bytes() {
+        let data = vec![0x82, 0x08, 0x00, 0x0A, 0x00, 0x03, 0x61, 0x2F, 0x62, 0x01];
+        let mut subscribe = Subscribe::from_bytes(data.clone()).unwrap();
+        subscribe.fixed_header.remaining_length = 0;
+        assert_eq!(subscribe.to_bytes(), data);
+    }
+
+    #[test]
+    fn test_subscribe_with_wrong_fixed_header_flags() {
+        for first_byte in [0x80, 0x83, 0x8A] {
+            let data = vec![first_byte, 0x08, 0x00, 0x0A, 0x00, 0x03, 0x61, 0x2F, 0x62, 0x01];
+            assert_eq!(
+                Subscribe::from_bytes(data).unwrap_err(),
+                MqttError::MalformedPacket("Reserved flags of the SUBSCRIBE fixed header are not 0010")
+            );
+        }
+    }
+
+    #[test]
+    fn test_subscribe_without_topic_filters() {
+        let data = vec![0x82, 0x02, 0x00, 0x0A];
+        assert_eq!(Subscribe::from_bytes(data).unwrap_err(), MqttError::MalformedPacket("SUBSCRIBE packet has no topic filters"));
+    }
+}
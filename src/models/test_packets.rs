@@ -0,0 +1,31 @@
+// Packet builders shared by the dispatcher tests and the connection tests of the server binary.
+// The binary includes this file by path, so it only uses std.
+
+// A UTF-8 string as it is encoded on the wire, prefixed with its length as two bytes
+pub fn length_prefixed(value: &str) -> Vec<u8> {
+    let mut bytes = (value.len() as u16).to_be_bytes().to_vec();
+    bytes.extend_from_slice(value.as_bytes());
+    bytes
+}
+
+// MQTT 3.1.1 CONNECT with a keep alive of 60 seconds, the given connect flags and payload after the client id
+pub fn connect_packet(client_id: &str, connect_flags: u8, extra_payload: &[u8]) -> Vec<u8> {
+    let mut body = vec![0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, 0x04, connect_flags, 0x00, 0x3C];
+    body.extend(length_prefixed(client_id));
+    body.extend_from_slice(extra_payload);
+    let mut packet = vec![0x10];
+    let mut remaining_length = body.len();
+    loop {
+        let mut encoded_byte = (remaining_length % 128) as u8;
+        remaining_length /= 128;
+        if remaining_length > 0 {
+            encoded_byte |= 0x80;
+        }
+        packet.push(encoded_byte);
+        if remaining_length == 0 {
+            break;
+        }
+    }
+    packet.extend(body);
+    packet
+}
@@ -0,0 +1,112 @@
+// Topic filter matching with the single level '+' and multi level '#' wildcards [MQTT-4.7]
+
+const LEVEL_SEPARATOR: char = '/';
+const SINGLE_LEVEL_WILDCARD: &str = "+";
+const MULTI_LEVEL_WILDCARD: &str = "#";
+
+// '#' must be the last level of a filter and both wildcards must occupy a whole level [MQTT-4.7.1-2], [MQTT-4.7.1-3]
+pub fn is_valid_filter(filter: &str) -> bool {
+    if filter.is_empty() {
+        return false;
+    }
+    let levels: Vec<&str> = filter.split(LEVEL_SEPARATOR).collect();
+    levels.iter().enumerate().all(|(index, level)| {
+        if level.contains('#') {
+            *level == MULTI_LEVEL_WILDCARD && index == levels.len() - 1
+        } else {
+            !level.contains('+') || *level == SINGLE_LEVEL_WILDCARD
+        }
+    })
+}
+
+// A topic name a message is published to is never empty and holds no wildcards [MQTT-4.7.3-1], [MQTT-3.3.2-2]
+pub fn is_valid_topic_name(topic: &str) -> bool {
+    !topic.is_empty() && !topic.contains(['+', '#'])
+}
+
+// Walks both level iterators in lock step without recursion, so a topic with a huge number of levels cannot overflow the stack
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    if !is_valid_filter(filter) || topic.is_empty() {
+        return false;
+    }
+    // Topics starting with '$' are not matched by a filter starting with a wildcard [MQTT-4.7.2-1]
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    let mut filter_levels = filter.split(LEVEL_SEPARATOR);
+    let mut topic_levels = topic.split(LEVEL_SEPARATOR);
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            // '#' also matches the parent level, so "sport/#" matches "sport"
+            (Some(MULTI_LEVEL_WILDCARD), _) => return true,
+            (Some(SINGLE_LEVEL_WILDCARD), Some(_)) => continue,
+            (Some(filter_level), Some(topic_level)) if filter_level == topic_level => continue,
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod topic_tests {
+    use super::*;
+
+    #[test]
+    fn test_exact_match() {
+        assert!(topic_matches("sport/tennis", "sport/tennis"));
+        assert!(!topic_matches("sport/tennis", "sport/tennis/player1"));
+        assert!(!topic_matches("sport/tennis", "sport"));
+    }
+
+    #[test]
+    fn test_multi_level_wildcard() {
+        assert!(topic_matches("sport/tennis/#", "sport/tennis/player1"));
+        assert!(topic_matches("sport/tennis/#", "sport/tennis/player1/ranking"));
+        assert!(topic_matches("sport/#", "sport"));
+        assert!(topic_matches("#", "sport/tennis"));
+        assert!(!topic_matches("sport/tennis/#", "sport/football"));
+    }
+
+    #[test]
+    fn test_single_level_wildcard() {
+        assert!(topic_matches("+/tennis/#", "sport/tennis/player1"));
+        assert!(topic_matches("sensors/+/temp", "sensors/kitchen/temp"));
+        assert!(!topic_matches("sensors/+/temp", "sensors/kitchen/floor/temp"));
+        assert!(!topic_matches("sport/+", "sport"));
+        assert!(topic_matches("sport/+", "sport/"));
+    }
+
+    #[test]
+    fn test_topic_names() {
+        assert!(is_valid_topic_name("sport/tennis"));
+        assert!(is_valid_topic_name("/"));
+        assert!(!is_valid_topic_name(""));
+        assert!(!is_valid_topic_name("sport/+"));
+        assert!(!is_valid_topic_name("#"));
+        assert!(!is_valid_topic_name("sport/tennis#"));
+    }
+
+    #[test]
+    fn test_invalid_filters_match_nothing() {
+        assert!(!is_valid_filter("sport/tennis#"));
+        assert!(!is_valid_filter("sport/#/ranking"));
+        assert!(!is_valid_filter("sport+"));
+        assert!(!topic_matches("sport/#/ranking", "sport/tennis/ranking"));
+    }
+
+    #[test]
+    fn test_deep_topics() {
+        let topic = vec!["a"; 5000].join("/");
+        assert!(topic_matches("#", &topic));
+        assert!(topic_matches(&topic, &topic));
+        assert!(topic_matches(&format!("{}/#", vec!["+"; 4999].join("/")), &topic));
+        assert!(!topic_matches(&vec!["+"; 4999].join("/"), &topic));
+    }
+
+    #[test]
+    fn test_dollar_topics() {
+        assert!(!topic_matches("#", "$SYS/uptime"));
+        assert!(!topic_matches("+/uptime", "$SYS/uptime"));
+        assert!(topic_matches("$SYS/#", "$SYS/uptime"));
+    }
+}
@@ -0,0 +1,213 @@
+// Byte sequences taken from the MQTT 3.1.1 specification
+// https://docs.solace.com/API/MQTT-311-Prtl-Conformance-Spec/MQTT%20Control%20Packets.htm
+// Each vector is decoded with the broker's parsers and, where the broker encodes that packet,
+// re-encoded to check the exact bytes are reproduced.
+
+use mqtt_broker::models::mqtt_headers::{ConnAckHeader, MqttHeaders};
+use mqtt_broker::models::mqtt_payloads::{self, Payload};
+use mqtt_broker::models::mqtt_types::MqttPacketType;
+use mqtt_broker::models::packets::connack::ConnAck;
+use mqtt_broker::models::packets::connect::Connect;
+use mqtt_broker::models::packets::publish::Publish;
+use mqtt_broker::models::packets::suback::SubAck;
+use mqtt_broker::models::packets::subscribe::Subscribe;
+
+// 2.2.3 Remaining Length: the boundaries of the variable length encoding
+const REMAINING_LENGTH_VECTORS: [(u32, &[u8]); 8] = [
+    (0, &[0x00]),
+    (127, &[0x7F]),
+    (128, &[0x80, 0x01]),
+    (16_383, &[0xFF, 0x7F]),
+    (16_384, &[0x80, 0x80, 0x01]),
+    (2_097_151, &[0xFF, 0xFF, 0x7F]),
+    (2_097_152, &[0x80, 0x80, 0x80, 0x01]),
+    (268_435_455, &[0xFF, 0xFF, 0xFF, 0x7F]),
+];
+
+#[test]
+fn remaining_length_decodes() {
+    for (remaining_length, encoded) in REMAINING_LENGTH_VECTORS {
+        let data = [&[0x30][..], encoded].concat();
+        let header = MqttHeaders::parse(&data).unwrap();
+        assert_eq!(header.remaining_length, remaining_length);
+        assert_eq!(header.incomming_byte_size(), data.len());
+    }
+}
+
+#[test]
+fn remaining_length_encodes() {
+    for (remaining_length, encoded) in REMAINING_LENGTH_VECTORS {
+        let header = MqttHeaders::new(MqttPacketType::Publish, 0, remaining_length);
+        assert_eq!(header.to_bytes(), [&[0x30][..], encoded].concat());
+    }
+}
+
+// 2.2.2 Flags: the reserved flag values for each control packet type
+#[test]
+fn fixed_header_flags() {
+    let vectors: [(&[u8], MqttPacketType, u8); 12] = [
+        (&[0x10, 0x00], MqttPacketType::Connect, 0b0000),
+        (&[0x20, 0x02], MqttPacketType::ConnAck, 0b0000),
+        (&[0x30, 0x00], MqttPacketType::Publish, 0b0000),
+        (&[0x40, 0x02], MqttPacketType::PubAck, 0b0000),
+        (&[0x50, 0x02], MqttPacketType::PubRec, 0b0000),
+        (&[0x62, 0x02], MqttPacketType::PubRel, 0b0010),
+        (&[0x70, 0x02], MqttPacketType::PubComp, 0b0000),
+        (&[0x82, 0x00], MqttPacketType::Subscribe, 0b0010),
+        (&[0x90, 0x03], MqttPacketType::SubAck, 0b0000),
+        (&[0xA2, 0x00], MqttPacketType::Unsubscribe, 0b0010),
+        (&[0xB0, 0x02], MqttPacketType::UnsubAck, 0b0000),
+        (&[0xE0, 0x00], MqttPacketType::Disconnect, 0b0000),
+    ];
+    for (encoded, packet_type, flags) in vectors {
+        let header = MqttHeaders::parse(encoded).unwrap();
+        assert_eq!(header.packet_type, packet_type);
+        assert_eq!(header.flags, flags);
+        assert_eq!(MqttHeaders::new(packet_type, flags, header.remaining_length).to_bytes(), encoded);
+    }
+}
+
+// 3.3.1 PUBLISH fixed header: DUP, QoS and RETAIN live in the flags nibble
+#[test]
+fn publish_fixed_header_flags() {
+    assert_eq!(MqttHeaders::parse(&[0x31, 0x00]).unwrap().flags, 0b0001); // QoS 0, RETAIN
+    assert_eq!(MqttHeaders::parse(&[0x32, 0x00]).unwrap().flags, 0b0010); // QoS 1
+    assert_eq!(MqttHeaders::parse(&[0x34, 0x00]).unwrap().flags, 0b0100); // QoS 2
+    assert_eq!(MqttHeaders::parse(&[0x3A, 0x00]).unwrap().flags, 0b1010); // DUP, QoS 1
+}
+
+// 3.12 PINGREQ and 3.13 PINGRESP are header only
+#[test]
+fn ping_packets() {
+    for (encoded, packet_type) in [([0xC0, 0x00], MqttPacketType::PingReq), ([0xD0, 0x00], MqttPacketType::PingResp)] {
+        let header = MqttHeaders::parse(&encoded).unwrap();
+        assert_eq!(header.packet_type, packet_type);
+        assert_eq!(header.remaining_length, 0);
+        assert_eq!(MqttHeaders::new(packet_type, 0, 0).to_bytes(), encoded);
+    }
+}
+
+// 3.1 CONNECT: protocol name "MQTT", level 4, clean session, keep alive 60, client id "test"
+#[test]
+fn connect_minimal() {
+    let data = vec![
+        0x10, 0x10, // fixed header
+        0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, // protocol name
+        0x04, // protocol level
+        0x02, // connect flags: clean session
+        0x00, 0x3C, // keep alive
+        0x00, 0x04, 0x74, 0x65, 0x73, 0x74, // client id
+    ];
+    let connect = Connect::from_bytes(data).unwrap();
+    assert_eq!(connect.fixed_header.remaining_length, 16);
+    assert_eq!(connect.variable_header.protocol_name, "MQTT");
+    assert_eq!(connect.variable_header.protocol_level, 4);
+    assert!(connect.variable_header.clean_session());
+    assert_eq!(connect.variable_header.keep_alive, 60);
+    match connect.payload {
+        Payload::Connect(payload) => assert_eq!(payload.client_id.unwrap(), "test"),
+        other => panic!("Expected ConnectPayload, found {:?}", other),
+    }
+}
+
+// 3.1 CONNECT: will (QoS 1), user name and password present
+#[test]
+fn connect_with_will_and_credentials() {
+    let data = vec![
+        0x10, 0x1F, // fixed header
+        0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, // protocol name
+        0x04, // protocol level
+        0xCE, // connect flags: user name, password, will QoS 1, will flag, clean session
+        0x00, 0x0A, // keep alive
+        0x00, 0x03, 0x63, 0x69, 0x64, // client id: cid
+        0x00, 0x03, 0x61, 0x2F, 0x62, // will topic: a/b
+        0x00, 0x03, 0x62, 0x79, 0x65, // will message: bye
+        0x00, 0x01, 0x75, // user name: u
+        0x00, 0x01, 0x70, // password: p
+    ];
+    let connect = Connect::from_bytes(data).unwrap();
+    assert!(connect.variable_header.will_flag());
+    assert_eq!(connect.variable_header.will_qos(), 1);
+    assert!(!connect.variable_header.will_retain());
+    match connect.payload {
+        Payload::Connect(payload) => {
+            assert_eq!(payload.client_id.unwrap(), "cid");
+            assert_eq!(payload.will_topic.unwrap(), "a/b");
+            assert_eq!(payload.will_message.unwrap(), "bye");
+            assert_eq!(payload.username.unwrap(), "u");
+            assert_eq!(payload.password.unwrap(), "p");
+        }
+        other => panic!("Expected ConnectPayload, found {:?}", other),
+    }
+}
+
+// 3.2 CONNACK: session present flag and each return code
+#[test]
+fn connack() {
+    for (session_present, return_code) in [(false, 0x00), (true, 0x00), (false, 0x01), (false, 0x02), (false, 0x03), (false, 0x04), (false, 0x05)] {
+        let encoded = vec![0x20, 0x02, session_present as u8, return_code];
+
+        let connack = ConnAck::from_bytes(encoded.clone()).unwrap();
+        assert_eq!(connack.variable_header.session_present, session_present);
+        assert_eq!(connack.variable_header.return_code, return_code);
+
+        let connack = ConnAck::new(
+            MqttHeaders::new(MqttPacketType::ConnAck, 0, 2),
+            ConnAckHeader::new(session_present, return_code),
+            Payload::Default(mqtt_payloads::Default),
+        );
+        assert_eq!(connack.to_bytes(), encoded);
+    }
+}
+
+// 3.3 PUBLISH: topic "a/b" and payload "hi" at each QoS, the packet identifier 10 only present from QoS 1 on
+#[test]
+fn publish() {
+    let vectors: [(&[u8], u8, bool, bool); 3] = [
+        (&[0x30, 0x07, 0x00, 0x03, 0x61, 0x2F, 0x62, 0x68, 0x69], 0, false, false),
+        (&[0x32, 0x09, 0x00, 0x03, 0x61, 0x2F, 0x62, 0x00, 0x0A, 0x68, 0x69], 1, false, false),
+        (&[0x3D, 0x09, 0x00, 0x03, 0x61, 0x2F, 0x62, 0x00, 0x0A, 0x68, 0x69], 2, true, true), // DUP and RETAIN
+    ];
+    for (encoded, qos, dup, retain) in vectors {
+        let publish = Publish::from_bytes(encoded.to_vec()).unwrap();
+        assert_eq!(publish.qos(), qos);
+        assert_eq!(publish.dup(), dup);
+        assert_eq!(publish.retain(), retain);
+        assert_eq!(publish.variable_header.topic_name, "a/b");
+        if qos > 0 {
+            assert_eq!(publish.variable_header.packet_id, 10);
+        }
+        assert_eq!(publish.payload_bytes(), b"hi");
+        assert_eq!(publish.to_bytes(), encoded);
+    }
+}
+
+// 3.8 SUBSCRIBE: packet identifier 10 with "a/b" at QoS 1 and "c/d" at QoS 2
+#[test]
+fn subscribe() {
+    let encoded = vec![
+        0x82, 0x0E, // fixed header
+        0x00, 0x0A, // packet identifier
+        0x00, 0x03, 0x61, 0x2F, 0x62, 0x01, // a/b, QoS 1
+        0x00, 0x03, 0x63, 0x2F, 0x64, 0x02, // c/d, QoS 2
+    ];
+    let subscribe = Subscribe::from_bytes(encoded.clone()).unwrap();
+    assert_eq!(subscribe.fixed_header.flags, 0b0010);
+    assert_eq!(subscribe.variable_header.packet_id, 10);
+    assert_eq!(subscribe.topic_filters(), vec![("a/b".to_string(), 1), ("c/d".to_string(), 2)]);
+    assert_eq!(subscribe.to_bytes(), encoded);
+}
+
+// 3.9 SUBACK: packet identifier 10 granting QoS 0, 1 and 2 and refusing the fourth filter
+#[test]
+fn suback() {
+    let encoded = vec![0x90, 0x06, 0x00, 0x0A, 0x00, 0x01, 0x02, 0x80];
+    let suback = SubAck::from_bytes(encoded.clone()).unwrap();
+    assert_eq!(suback.variable_header.packet_id, 10);
+    match &suback.payload {
+        Payload::SubAck(payload) => assert_eq!(payload.return_codes, vec![0x00, 0x01, 0x02, SubAck::FAILURE_RETURN_CODE]),
+        other => panic!("Expected SubAckPayload, found {:?}", other),
+    }
+    assert_eq!(suback.to_bytes(), encoded);
+    assert_eq!(SubAck::new_granted(10, vec![0x00, 0x01, 0x02, 0x80]).to_bytes(), encoded);
+}